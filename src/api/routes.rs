@@ -1,40 +1,146 @@
-use actix_multipart::Multipart;
-use actix_web::{post, put, web, Responder};
-use futures_util::TryStreamExt as _;
-use log::{error, trace};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::SystemTime,
+};
+
+use actix_multipart::{Field, Multipart};
+use actix_web::{
+    error::PayloadError, get, http::header, post, put, routes, web, HttpMessage, HttpRequest,
+    HttpResponse, Responder, ResponseError,
+};
+use futures_util::{stream, TryStreamExt as _};
+use log::{error, info, trace, warn};
+use serde_json::json;
+use uuid::Uuid;
 
 use crate::utils::{
+    attachments::{check_types, sanitize_names, scan_files},
+    auth::authorize,
+    captcha::verify,
+    config::{AuthMethod, Recipients},
+    cors::{check_origin, preflight_response},
     errors::ServiceError,
-    mailer::{message_worker, Msg},
+    fields::{unknown, validate},
+    form_guard::{check_form, create_token},
+    ip_extrator::IpExtractor,
+    mailer::{message_worker, Msg, RequestMeta},
+    queue::delivery_status,
+    spam::{verdict, SpamVerdict},
 };
+use crate::CONFIG;
 
-/// This Rust code handles HTTP POST and PUT requests related to sending emails.
+/// Limit from multipart requests, attachments and form fields together.
+const REQUEST_LIMIT: &str = "max_attachment_size_mb + max_json_size_kb";
+
+// This Rust code handles HTTP POST and PUT requests related to sending emails.
 
 /// The **post_mail** function is an asynchronous function that handles POST requests to the
 /// "/mail/{direction}/" endpoint. The **{direction}** in the URL is a path parameter, which
 /// is captured and passed to the function as the **direction** argument. The function also
-/// accepts a JSON payload in the request body, which is deserialized into a **Msg** struct.
-/// The **direction** is then added to the **Msg** struct. The **send** function is called
-/// with the **Msg** struct to send the email. If the email is sent successfully, the function
-/// returns a success message.If there is an error, it logs the error and returns an
-/// **InternalServerError** response.
+/// accepts a JSON or urlencoded payload in the request body, which is deserialized into a
+/// **Msg** struct. Unknown directions get a **NotFound** response. The request is then checked
+/// by **send_post**.
+///
+/// For urlencoded requests from plain HTML forms, the function answers with **303 See Other**
+/// to **success_redirect** or **error_redirect** from the direction, when they are set. The
+/// error redirect gets the status code in the **error** query parameter.
+///
+/// When **async_response** is enabled, the function returns **202 Accepted** with the message ID,
+/// which can be used to check the delivery status.
 #[post("/mail/{direction}/")]
 pub async fn post_mail(
+    req: HttpRequest,
     direction: web::Path<String>,
-    mut msg: web::Json<Msg>,
-) -> Result<impl Responder, ServiceError> {
-    msg.direction = Some(direction.into_inner());
+    payload: web::Payload,
+) -> Result<HttpResponse, ServiceError> {
+    let direction = resolve_direction(&req, direction.into_inner())?;
+    let result = send_post(&req, &direction, payload).await;
+
+    if is_form(&req) {
+        if let Some(response) = CONFIG
+            .recipient(&direction)
+            .and_then(|r| form_redirect(r, &result))
+        {
+            return Ok(response);
+        }
+    }
+
+    result
+}
+
+/// The **send_post** function checks and sends the message from a POST request. Requests from
+/// origins which are not allowed get a **Forbidden** response and requests without valid
+/// credentials for the direction get an **Unauthorized** response. Bodies and attachments over the
+/// limits get a **PayloadTooLarge** response, bodies which can not be parsed a **BadRequest**.
+/// Afterwards the **direction** is added to the **Msg** struct. Messages which fail the honeypot
+/// or token check get a fake success response.
+///
+/// When the direction has a **captcha**, the response token is verified at the provider, failed
+/// verifications get an **UnprocessableEntity** response and an unreachable provider a
+/// **ServiceUnavailable**. Unknown fields get a **Conflict** response, invalid fields, rejected
+/// spam and attachments which are not allowed get an **UnprocessableEntity** response. At last
+/// the **message_worker** function is called with the **Msg** struct to queue the email, when
+/// this fails it returns an **InternalServerError** response.
+async fn send_post(
+    req: &HttpRequest,
+    direction: &str,
+    payload: web::Payload,
+) -> Result<HttpResponse, ServiceError> {
+    check_cors(req, direction)?;
+    let json_limit = format!("{} KB", CONFIG.max_json_size_kb);
+    check_content_length(
+        req,
+        CONFIG.max_json_bytes(),
+        "max_json_size_kb",
+        &json_limit,
+    )?;
+    let body = read_body(
+        payload,
+        CONFIG.max_json_bytes(),
+        "max_json_size_kb",
+        json_limit,
+    )
+    .await?;
+
+    check_auth(req, direction, &body)?;
+
+    let mut msg = if is_form(req) {
+        form_msg(&body, direction)?
+    } else {
+        serde_json::from_slice(&body).map_err(|e| ServiceError::BadRequest(e.to_string()))?
+    };
+    msg.direction = Some(direction.to_string());
+    msg.meta = request_meta(req);
 
     trace!("Msg: {:?}", msg.clone());
 
-    if msg.is_spam() {
-        return Err(ServiceError::UnprocessableEntity(
-            "Message contains blocked words".to_string(),
-        ));
+    if let Some(files) = &msg.attachment {
+        check_limits(files)?;
+    }
+
+    if let Some(response) = check_bot(req, &msg) {
+        return Ok(response);
     }
 
-    match message_worker(msg.into_inner()).await {
-        Ok(_) => Ok("Send success!"),
+    check_captcha(req, &msg).await?;
+    check_fields(&msg)?;
+
+    if let Some(files) = msg.attachment.as_mut() {
+        sanitize_names(files);
+    }
+
+    check_spam(&mut msg)?;
+
+    if let (Some(files), Some(recipient)) = (
+        &msg.attachment,
+        msg.direction.as_ref().and_then(|d| CONFIG.recipient(d)),
+    ) {
+        check_types(recipient, files)?;
+        scan_files(recipient, files).await?;
+    }
+
+    match message_worker(msg).await {
+        Ok(id) => Ok(success_response(id)),
         Err(_) => Err(ServiceError::InternalServerError),
     }
 }
@@ -47,77 +153,539 @@ pub async fn post_mail(
 /// **content_disposition** of "mail", "subject", or "text", the function reads the next chunk of
 /// data from the field and converts it to a string. If the field has a different
 /// **content_disposition**, the function assumes it's a file and reads the file data into a
-/// buffer. The filename and buffer are then added to the **files** vector. If the field has a
-/// content_disposition that the function doesn't recognize, it logs an error and returns a
-/// **Conflict** response.
+/// buffer. The filename and buffer are then added to the **files** vector. All other fields
+/// without filename are custom form fields, which are checked with the fields from the direction.
+///
+/// Files are checked while they are streamed, against **max_attachments**, **max_file_size_mb**
+/// and **max_attachment_size_mb**. When one of these limits is exceeded, the request is stopped
+/// with a **PayloadTooLarge** response. Files which are not allowed for the direction, get an
+/// **UnprocessableEntity** response with the filename. All filenames are sanitized before.
+/// Afterwards the files are scanned for viruses, when this is enabled for the direction.
+///
+/// The form fields **token**, the honeypot field and the CAPTCHA response from the direction are
+/// checked like in **post_mail**, before the attachments are checked. Credentials are checked
+/// before the form is read, for HMAC signatures the whole body is read first.
 #[put("/mail/{direction}/")]
 pub async fn put_mail_attachment(
+    req: HttpRequest,
     direction: web::Path<String>,
-    mut payload: Multipart,
+    payload: web::Payload,
 ) -> Result<impl Responder, ServiceError> {
+    let direction = resolve_direction(&req, direction.into_inner())?;
+    check_cors(&req, &direction)?;
+    let auth = CONFIG.recipient(&direction).and_then(|r| r.auth.as_ref());
+    // the whole request has room for the attachments and the form fields
+    let request_limit = CONFIG.max_attachment_bytes() + CONFIG.max_json_bytes();
+    let request_maximum = format!("{request_limit} bytes");
+
+    check_content_length(&req, request_limit, REQUEST_LIMIT, &request_maximum)?;
+
+    let mut payload = if auth.is_some_and(|a| a.method == AuthMethod::Hmac) {
+        // the signature is over the whole body, so it must be read before the form is parsed
+        let body = read_body(payload, request_limit, REQUEST_LIMIT, request_maximum).await?;
+
+        check_auth(&req, &direction, &body)?;
+
+        Multipart::new(
+            req.headers(),
+            stream::once(async { Ok::<_, PayloadError>(body) }),
+        )
+    } else {
+        check_auth(&req, &direction, &[])?;
+
+        Multipart::new(req.headers(), payload)
+    };
+    let extra_fields = CONFIG
+        .recipient(&direction)
+        .map(|r| r.extra_fields())
+        .unwrap_or_default();
     let mut files = vec![];
+    let mut fields = BTreeMap::new();
+    let mut extra = HashMap::new();
+    let mut total_size = 0;
+    let mut form = FormSize::default();
     let mut mail = String::new();
     let mut subject = String::new();
     let mut text = String::new();
+    let mut token = String::new();
 
     while let Some(mut field) = payload.try_next().await? {
         let content_disposition = field.content_disposition().clone();
         if let Some(name) = content_disposition.get_name() {
             match name {
-                "mail" => {
-                    if let Some(chunk) = field.try_next().await? {
-                        mail = String::from_utf8_lossy(&chunk).to_string();
-                    }
-                }
-                "subject" => {
-                    if let Some(chunk) = field.try_next().await? {
-                        subject = String::from_utf8_lossy(&chunk).to_string();
-                    }
-                }
-                "text" => {
-                    if let Some(chunk) = field.try_next().await? {
-                        text = String::from_utf8_lossy(&chunk).to_string();
-                    }
+                "mail" => mail = read_field(&mut field, &mut form).await?,
+                "subject" => subject = read_field(&mut field, &mut form).await?,
+                "text" => text = read_field(&mut field, &mut form).await?,
+                "token" => token = read_field(&mut field, &mut form).await?,
+                name if extra_fields.contains(&name) => {
+                    let value = read_field(&mut field, &mut form).await?;
+                    extra.insert(name.to_string(), value.into());
                 }
                 _ => {
                     if let Some(filename) = content_disposition.get_filename() {
                         let mut buffer: Vec<u8> = vec![];
 
+                        if files.len() >= CONFIG.max_attachments {
+                            return Err(limit_exceeded(
+                                "max_attachments",
+                                format!("{} files", CONFIG.max_attachments),
+                            ));
+                        }
+
                         while let Some(chunk) = field.try_next().await? {
-                            for slices in chunk.iter().copied() {
-                                buffer.push(slices);
+                            total_size += chunk.len();
+
+                            if buffer.len() + chunk.len() > CONFIG.max_file_bytes() {
+                                return Err(limit_exceeded(
+                                    "max_file_size_mb",
+                                    format!("{} bytes", CONFIG.max_file_bytes()),
+                                ));
+                            }
+
+                            if total_size > CONFIG.max_attachment_bytes() {
+                                return Err(limit_exceeded(
+                                    "max_attachment_size_mb",
+                                    format!("{} bytes", CONFIG.max_attachment_bytes()),
+                                ));
                             }
+
+                            buffer.extend_from_slice(&chunk);
                         }
 
                         files.push((filename.to_string(), buffer));
                     } else {
-                        error!("Unknown form data: {name}");
-                        return Err(ServiceError::Conflict(format!("Unknown form data: {name}")));
+                        // custom form field, it is checked with the fields from the direction
+                        let value = read_field(&mut field, &mut form).await?;
+                        fields.insert(name.to_string(), value);
                     }
                 }
             }
         }
     }
 
-    let msg = Msg::new(
-        Some(direction.into_inner()),
-        false,
-        mail,
-        subject,
-        text,
-        Some(files),
-    );
+    sanitize_names(&mut files);
+
+    let mut msg = Msg::new(Some(direction), false, mail, subject, text, Some(files));
+    msg.token = token;
+    msg.fields = fields;
+    msg.extra = extra;
+    msg.meta = request_meta(&req);
 
     trace!("Msg: {msg:?}");
 
-    if msg.is_spam() {
-        return Err(ServiceError::UnprocessableEntity(
-            "Message contains blocked words".to_string(),
-        ));
+    if let Some(response) = check_bot(&req, &msg) {
+        return Ok(response);
     }
 
+    check_captcha(&req, &msg).await?;
+    check_fields(&msg)?;
+
+    if let (Some(files), Some(recipient)) = (
+        &msg.attachment,
+        msg.direction.as_ref().and_then(|d| CONFIG.recipient(d)),
+    ) {
+        check_types(recipient, files)?;
+        scan_files(recipient, files).await?;
+    }
+
+    check_spam(&mut msg)?;
+
     match message_worker(msg).await {
-        Ok(_) => Ok("Send success!"),
+        Ok(id) => Ok(success_response(id)),
         Err(_) => Err(ServiceError::InternalServerError),
     }
 }
+
+/// The **get_status** function handles GET requests to the "/mail/status/{id}" endpoint. It reports
+/// the delivery status from a message, which is **queued**, **retrying**, **sent** or **failed**,
+/// together with the number of attempts and the last SMTP error, which has only the reply codes without
+/// **status_errors**.
+#[get("/mail/status/{id}")]
+pub async fn get_status(id: web::Path<String>) -> Result<impl Responder, ServiceError> {
+    let id =
+        Uuid::parse_str(&id).map_err(|_| ServiceError::BadRequest("Invalid ID".to_string()))?;
+
+    match delivery_status(&id) {
+        Some(status) => Ok(web::Json(status)),
+        None => Err(ServiceError::NotFound("Message not found".to_string())),
+    }
+}
+
+/// The **get_token** function handles GET requests to the "/mail/{direction}/token" endpoint. It returns
+/// a signed timestamp token, which the form sends back in the **token** field. Directions with
+/// **form_token** enabled only accept messages with a token, which is between **min_fill_seconds**
+/// and **max_fill_minutes** old.
+#[get("/mail/{direction}/token")]
+pub async fn get_token(
+    req: HttpRequest,
+    direction: web::Path<String>,
+) -> Result<impl Responder, ServiceError> {
+    let direction = resolve_direction(&req, direction.into_inner())?;
+    check_cors(&req, &direction)?;
+
+    Ok(web::Json(json!({ "token": create_token(&direction) })))
+}
+
+/// The **preflight** function answers CORS preflight requests for the mail and token routes. Origins,
+/// methods and headers which are not allowed for the direction get a **Forbidden** response.
+#[routes]
+#[options("/mail/{direction}/")]
+#[options("/mail/{direction}/token")]
+pub async fn preflight(
+    req: HttpRequest,
+    direction: web::Path<String>,
+) -> Result<impl Responder, ServiceError> {
+    let direction = resolve_direction(&req, direction.into_inner())?;
+
+    preflight_response(&req, CONFIG.cors(&direction)).map_err(|reason| {
+        warn!(
+            "CORS preflight from {} for \"{direction}\" rejected: {reason}",
+            client_ip(&req)
+        );
+
+        ServiceError::Forbidden("Origin not allowed".to_string())
+    })
+}
+
+/// The **resolve_direction** function checks the direction from the URL before anything else is done.
+/// Unknown directions are answered with **NotFound** and logged with the client IP, because they are
+/// mostly probes. When **default_direction** is set, unknown directions fall back to it.
+fn resolve_direction(req: &HttpRequest, direction: String) -> Result<String, ServiceError> {
+    if CONFIG.recipient(&direction).is_some() {
+        return Ok(direction);
+    }
+
+    let ip = client_ip(req);
+
+    if !CONFIG.mail.default_direction.is_empty() {
+        info!(
+            "Unknown direction \"{direction}\" from {ip}, use default direction \"{}\"",
+            CONFIG.mail.default_direction
+        );
+
+        return Ok(CONFIG.mail.default_direction.clone());
+    }
+
+    warn!("Probe attempt from {ip}, unknown direction: \"{direction}\"");
+
+    Err(ServiceError::NotFound("Unknown direction".to_string()))
+}
+
+/// The **check_cors** function rejects requests from origins, which are not allowed for the direction.
+/// It runs before the body is read, so no other checks are done for these requests.
+fn check_cors(req: &HttpRequest, direction: &str) -> Result<(), ServiceError> {
+    check_origin(req, CONFIG.cors(direction)).map_err(|reason| {
+        warn!(
+            "Request from {} for \"{direction}\" rejected: {reason}",
+            client_ip(req)
+        );
+
+        ServiceError::Forbidden("Origin not allowed".to_string())
+    })
+}
+
+/// The **check_auth** function checks the credentials, when the direction requires authentication.
+/// Failed attempts are logged with the client IP and get an **Unauthorized** response.
+fn check_auth(req: &HttpRequest, direction: &str, body: &[u8]) -> Result<(), ServiceError> {
+    let Some(auth) = CONFIG.recipient(direction).and_then(|r| r.auth.as_ref()) else {
+        return Ok(());
+    };
+
+    authorize(req, auth, body).map_err(|reason| {
+        warn!(
+            "Unauthorized request from {} for \"{direction}\": {reason}",
+            client_ip(req)
+        );
+
+        ServiceError::Unauthorized("Unauthorized".to_string())
+    })
+}
+
+/// The **check_bot** function runs the honeypot and token checks from the direction. Messages from bots
+/// get a fake success response, so they don't learn that the message was dropped.
+fn check_bot(req: &HttpRequest, msg: &Msg) -> Option<HttpResponse> {
+    let recipient = msg.direction.as_ref().and_then(|d| CONFIG.recipient(d))?;
+    let reason = check_form(msg, recipient).err()?;
+
+    warn!(
+        "Drop message from {} for \"{}\": {reason}",
+        client_ip(req),
+        recipient.direction
+    );
+
+    Some(success_response(Uuid::new_v4().to_string()))
+}
+
+/// The **check_captcha** function verifies the response token from the CAPTCHA widget,
+/// when a CAPTCHA is configured for the direction.
+async fn check_captcha(req: &HttpRequest, msg: &Msg) -> Result<(), ServiceError> {
+    let Some(captcha) = msg
+        .direction
+        .as_ref()
+        .and_then(|d| CONFIG.recipient(d))
+        .and_then(|r| r.captcha.as_ref())
+    else {
+        return Ok(());
+    };
+
+    let response = msg
+        .extra
+        .get(captcha.provider.field())
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+
+    verify(captcha, response, IpExtractor::real_ip(req).ok())
+        .await
+        .inspect_err(|_| warn!("CAPTCHA check failed from {}", client_ip(req)))
+}
+
+/// The **check_fields** function validates the custom form fields with the field list from the direction.
+/// Unknown fields get a **Conflict** response, when the direction does not accept them,
+/// missing or invalid fields get an **UnprocessableEntity** response.
+fn check_fields(msg: &Msg) -> Result<(), ServiceError> {
+    let Some(recipient) = msg.direction.as_ref().and_then(|d| CONFIG.recipient(d)) else {
+        return Ok(());
+    };
+
+    if !recipient.accept_unknown_fields {
+        if let Some(name) = unknown(&msg.fields, &recipient.fields) {
+            return Err(ServiceError::Conflict(format!("Unknown form data: {name}")));
+        }
+    }
+
+    validate(&msg.fields, &recipient.fields).map_err(ServiceError::UnprocessableEntity)
+}
+
+/// The **check_spam** function scores the message with the spam rules. Messages over the reject
+/// score get an **UnprocessableEntity** response, messages over the quarantine score are marked,
+/// so they are kept in quarantine instead of sent.
+fn check_spam(msg: &mut Msg) -> Result<(), ServiceError> {
+    let Some(recipient) = msg.direction.as_ref().and_then(|d| CONFIG.recipient(d)) else {
+        return Ok(());
+    };
+
+    match verdict(msg, recipient) {
+        SpamVerdict::Reject(_) => Err(ServiceError::UnprocessableEntity(
+            "Message contains blocked words".to_string(),
+        )),
+        SpamVerdict::Quarantine(_) => {
+            msg.quarantine = true;
+            Ok(())
+        }
+        SpamVerdict::Ham => Ok(()),
+    }
+}
+
+/// Request comes from a plain HTML form.
+fn is_form(req: &HttpRequest) -> bool {
+    req.content_type() == mime::APPLICATION_WWW_FORM_URLENCODED.essence_str()
+}
+
+/// Build the message from urlencoded form fields. The honeypot and CAPTCHA fields are kept
+/// for the form checks, all other fields are custom fields.
+fn form_msg(body: &[u8], direction: &str) -> Result<Msg, ServiceError> {
+    let extra_fields = CONFIG
+        .recipient(direction)
+        .map(|r| r.extra_fields())
+        .unwrap_or_default();
+    let pairs: Vec<(String, String)> =
+        serde_urlencoded::from_bytes(body).map_err(|e| ServiceError::BadRequest(e.to_string()))?;
+    let mut msg = Msg::new(
+        None,
+        false,
+        String::new(),
+        String::new(),
+        String::new(),
+        None,
+    );
+
+    for (key, value) in pairs {
+        match key.as_str() {
+            "mail" => msg.mail = value,
+            "subject" => msg.subject = value,
+            "text" => msg.text = value,
+            "token" => msg.token = value,
+            _ if extra_fields.contains(&key.as_str()) => {
+                msg.extra.insert(key, value.into());
+            }
+            _ => {
+                msg.fields.insert(key, value);
+            }
+        }
+    }
+
+    Ok(msg)
+}
+
+/// Redirect plain HTML forms after the post, errors get the status code as **error** parameter.
+fn form_redirect(
+    recipient: &Recipients,
+    result: &Result<HttpResponse, ServiceError>,
+) -> Option<HttpResponse> {
+    let location = match result {
+        Ok(_) if !recipient.success_redirect.is_empty() => recipient.success_redirect.clone(),
+        Err(e) if !recipient.error_redirect.is_empty() => {
+            let separator = if recipient.error_redirect.contains('?') {
+                '&'
+            } else {
+                '?'
+            };
+
+            format!(
+                "{}{separator}error={}",
+                recipient.error_redirect,
+                e.error_response().status().as_u16()
+            )
+        }
+        _ => return None,
+    };
+
+    Some(
+        HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, location))
+            .finish(),
+    )
+}
+
+/// Real client IP for logging, also behind the reverse proxy.
+fn client_ip(req: &HttpRequest) -> String {
+    IpExtractor::real_ip(req)
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|e| e.to_string())
+}
+
+/// Client IP, User-Agent and receive time for the templates.
+fn request_meta(req: &HttpRequest) -> RequestMeta {
+    RequestMeta {
+        ip: client_ip(req),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string(),
+        received: fastdate::DateTime::from(SystemTime::now())
+            .set_offset(fastdate::offset_sec())
+            .format("YYYY-MM-DD hh:mm:ss +00:00"),
+    }
+}
+
+/// Read the whole request body, bodies over the limit get a **PayloadTooLarge** response.
+async fn read_body(
+    mut payload: web::Payload,
+    limit: usize,
+    limit_name: &str,
+    maximum: String,
+) -> Result<web::Bytes, ServiceError> {
+    let mut body = web::BytesMut::new();
+
+    while let Some(chunk) = payload.try_next().await? {
+        if body.len() + chunk.len() > limit {
+            return Err(limit_exceeded(limit_name, maximum));
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body.freeze())
+}
+
+/// Reject requests early, when the announced body is larger than the limit.
+fn check_content_length(
+    req: &HttpRequest,
+    limit: usize,
+    limit_name: &str,
+    maximum: &str,
+) -> Result<(), ServiceError> {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+
+    match length {
+        Some(length) if length > limit => Err(limit_exceeded(limit_name, maximum.to_string())),
+        _ => Ok(()),
+    }
+}
+
+/// Number and size from the form fields of a multipart request, without files.
+#[derive(Default)]
+struct FormSize {
+    count: usize,
+    bytes: usize,
+}
+
+/// Read a form field from a multipart request, all form fields together must stay in
+/// **max_form_fields** and **max_json_size_kb**.
+async fn read_field(field: &mut Field, form: &mut FormSize) -> Result<String, ServiceError> {
+    let mut value = vec![];
+    form.count += 1;
+
+    if form.count > CONFIG.max_form_fields {
+        return Err(limit_exceeded(
+            "max_form_fields",
+            format!("{} fields", CONFIG.max_form_fields),
+        ));
+    }
+
+    while let Some(chunk) = field.try_next().await? {
+        form.bytes += chunk.len();
+
+        if form.bytes > CONFIG.max_json_bytes() {
+            return Err(limit_exceeded(
+                "max_json_size_kb",
+                format!("{} KB", CONFIG.max_json_size_kb),
+            ));
+        }
+
+        value.extend_from_slice(&chunk);
+    }
+
+    Ok(String::from_utf8_lossy(&value).to_string())
+}
+
+/// Attachments from JSON requests get the same limits, like files from multipart requests.
+fn check_limits(files: &[(String, Vec<u8>)]) -> Result<(), ServiceError> {
+    if files.len() > CONFIG.max_attachments {
+        return Err(limit_exceeded(
+            "max_attachments",
+            format!("{} files", CONFIG.max_attachments),
+        ));
+    }
+
+    if files
+        .iter()
+        .any(|(_, data)| data.len() > CONFIG.max_file_bytes())
+    {
+        return Err(limit_exceeded(
+            "max_file_size_mb",
+            format!("{} bytes", CONFIG.max_file_bytes()),
+        ));
+    }
+
+    if files.iter().map(|(_, data)| data.len()).sum::<usize>() > CONFIG.max_attachment_bytes() {
+        return Err(limit_exceeded(
+            "max_attachment_size_mb",
+            format!("{} bytes", CONFIG.max_attachment_bytes()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Error for requests, which are over one of the configured limits.
+fn limit_exceeded(limit: &str, maximum: String) -> ServiceError {
+    error!("Request exceeds limit {limit} ({maximum})");
+
+    ServiceError::PayloadTooLarge(format!("Limit {limit} exceeded, maximum is {maximum}"))
+}
+
+/// Response after the message is queued, with **async_response** the message ID is returned.
+fn success_response(id: String) -> HttpResponse {
+    if CONFIG.async_response {
+        HttpResponse::Accepted().json(json!({ "id": id, "status": "queued" }))
+    } else {
+        HttpResponse::Ok().body("Send success!")
+    }
+}
@@ -1,39 +1,124 @@
 use std::{net::IpAddr, str::FromStr};
 
 use actix_governor::{Governor, GovernorConfigBuilder};
-use actix_web::{middleware, web, App, HttpServer};
+use actix_web::{dev::Service as _, middleware, web, App, HttpServer};
 use clap::Parser;
 use lazy_static::lazy_static;
-use log::{error, info};
+use log::{error, info, warn};
+use uuid::Uuid;
 
 pub mod api;
 pub mod utils;
 
-use api::routes::{post_mail, put_mail_attachment};
+use api::routes::{get_status, get_token, post_mail, preflight, put_mail_attachment};
 use utils::{
     arg_parser::Args,
-    config::{read_config, Config},
+    config::{read_config, SharedConfig},
+    cors,
     ip_extrator::IpExtractor,
     logging::init_logger,
     mailer::cli_message,
+    queue::{delivery_status, process_queue, queue_worker},
+    transport::reload_transports,
 };
 
+/// Exit code from sendmail, when the message is queued but not delivered.
+const EX_TEMPFAIL: i32 = 75;
+
 lazy_static! {
     pub static ref ARGS: Args = Args::parse();
-    pub static ref CONFIG: Config = read_config(&ARGS.config).expect("Missing Config");
+    pub static ref CONFIG: SharedConfig =
+        SharedConfig::new(read_config(&ARGS.config).expect("Missing Config"));
+}
+
+/// Read the config again on SIGHUP and rebuild the SMTP transports, this closes pooled connections and
+/// reads the CA bundle files again. A config with errors is not used, the current config stays active.
+///
+/// The listening address, routes, rate limit and logging are set up at startup, they need a restart.
+#[cfg(unix)]
+async fn reload_on_hangup() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Register SIGHUP handler: {e}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reload config and rebuild SMTP transports");
+
+        match read_config(&ARGS.config) {
+            Ok(mut config) => {
+                // without secret the form key is random, a new one would invalidate all open forms
+                if config.form_secret.is_empty() {
+                    config.form_key = CONFIG.form_key.clone();
+                }
+
+                CONFIG.replace(config);
+                reload_transports();
+
+                for warning in CONFIG.deprecations() {
+                    warn!("{warning}");
+                }
+            }
+            Err(e) => error!("Config not reloaded, keep the current config: {e}"),
+        }
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     init_logger()?;
 
+    for warning in CONFIG.deprecations() {
+        warn!("{warning}");
+    }
+
+    if ARGS.queue {
+        // queue run from a timer or cron job, without server
+        if let Err(e) = process_queue().await {
+            eprintln!("{e}");
+            std::process::exit(EX_TEMPFAIL);
+        }
+
+        return Ok(());
+    }
+
     if ARGS.subject.is_some() || ARGS.full_name.is_some() || ARGS.text {
         // send mails from CLI
-        if let Err(e) = cli_message().await {
+        let queued = cli_message().await;
+
+        // try to deliver directly, what is left stays in the spool for the next run
+        if let Err(e) = process_queue().await {
             eprintln!("{e}");
         }
 
-        return Ok(());
+        let id = match queued {
+            Ok(id) => id,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        };
+
+        let status = Uuid::parse_str(&id)
+            .ok()
+            .and_then(|uuid| delivery_status(&uuid));
+
+        match status.map(|s| s.status) {
+            Some("sent") => return Ok(()),
+            Some("failed") => {
+                eprintln!("Message {id} failed, it is moved to the dead letter folder");
+                std::process::exit(1);
+            }
+            _ => {
+                eprintln!("Message {id} is not delivered, it stays in the spool for the next run");
+                std::process::exit(EX_TEMPFAIL);
+            }
+        }
     }
 
     let addr_port = match &ARGS.listen {
@@ -45,6 +130,15 @@ async fn main() -> std::io::Result<()> {
     if let Some((addr, port)) = addr_port.split_once(':') {
         info!("Running mailpeter, listen on http://{addr}:{port}");
 
+        // build SMTP transports once, all messages share their connection pools
+        reload_transports();
+
+        // deliver queued messages in background
+        actix_web::rt::spawn(queue_worker());
+
+        #[cfg(unix)]
+        actix_web::rt::spawn(reload_on_hangup());
+
         let trusted_proxy_ip = IpAddr::from_str(&CONFIG.reverse_proxy_ip).expect("Proxy IP");
         let mut enable_limit = false;
         let mut limit = 1;
@@ -65,26 +159,48 @@ async fn main() -> std::io::Result<()> {
         HttpServer::new(move || {
             let mut app = App::new()
                 .app_data(web::Data::new(trusted_proxy_ip))
-                .wrap(middleware::Condition::new(
-                    enable_limit,
-                    Governor::new(&governor_conf),
-                ))
+                .wrap_fn(|req, srv| {
+                    // add CORS headers to all responses, also to errors and rate limited requests
+                    let res = srv.call(req);
+
+                    async move {
+                        let mut res = res.await?;
+                        cors::add_headers(&mut res);
+
+                        Ok(res)
+                    }
+                })
                 .wrap(middleware::Logger::new(
                     // custom logging format to get real IP behind proxy
                     "%{r}a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T",
                 ));
 
+            if CONFIG.async_response {
+                // activate route to check the delivery status from queued messages,
+                // it is registered outside the rate limit, so clients can poll it
+                app = app.service(get_status);
+            }
+
+            // forms get their token and the preflight before the message is sent,
+            // so these routes are also outside the rate limit
+            app = app.service(get_token).service(preflight);
+
+            let mut mail_routes = web::scope("").wrap(middleware::Condition::new(
+                enable_limit,
+                Governor::new(&governor_conf),
+            ));
+
             if CONFIG.routes.contains(&"text_only".to_string()) {
                 // activate route for text and html messages, accept json format
-                app = app.service(post_mail);
+                mail_routes = mail_routes.service(post_mail);
             }
 
             if CONFIG.routes.contains(&"with_attachments".to_string()) {
                 // activate route with attachment support, accept multipart/form-data format
-                app = app.service(put_mail_attachment);
+                mail_routes = mail_routes.service(put_mail_attachment);
             }
 
-            app
+            app.service(mail_routes)
         })
         .bind((addr.to_string(), port.parse().unwrap_or_default()))?
         .run()
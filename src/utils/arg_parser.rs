@@ -37,6 +37,13 @@ pub struct Args {
     )]
     pub level: Option<String>,
 
+    #[clap(
+        short,
+        long,
+        help = "Deliver the mails from the spool, which are due, and exit"
+    )]
+    pub queue: bool,
+
     #[clap(help = "Mail recipient for command line usage")]
     pub recipient: Option<String>,
 
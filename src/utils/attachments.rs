@@ -0,0 +1,293 @@
+use std::path::Path;
+
+use log::{debug, error, warn};
+use sanitize_filename::{sanitize_with_options, Options};
+
+use crate::utils::{
+    clamav::{scan, ScanResult},
+    config::{Recipients, VirusScan},
+    errors::ServiceError,
+};
+
+/// Extensions which belong to the same file type. Office documents are zip archives,
+/// if the exact type can not be detected, they are sniffed as zip.
+const EXTENSION_GROUPS: [&[&str]; 6] = [
+    &["jpg", "jpeg", "jpe", "jfif"],
+    &["tif", "tiff"],
+    &["mpg", "mpeg"],
+    &["mid", "midi"],
+    &["htm", "html"],
+    &["zip", "docx", "xlsx", "pptx", "odt", "ods", "odp"],
+];
+
+/// Maximum length from attachment filenames in characters
+const MAX_FILENAME_LENGTH: usize = 100;
+
+/// Sniff the mime type from the file content, **application/octet-stream** is the fallback.
+pub fn mime_type(data: &[u8]) -> &'static str {
+    match infer::get(data) {
+        Some(kind) => kind.mime_type(),
+        None => "application/octet-stream",
+    }
+}
+
+fn extension(filename: &str) -> Option<String> {
+    Path::new(filename)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+}
+
+/// Mime patterns can end with a wildcard, like **image/*.**
+fn mime_matches(patterns: &[String], mime: &str) -> bool {
+    patterns.iter().any(|p| {
+        let p = p.to_lowercase();
+
+        match p.strip_suffix("/*") {
+            Some(prefix) => mime.split('/').next() == Some(prefix),
+            None => p == mime,
+        }
+    })
+}
+
+fn extension_matches(declared: &str, sniffed: &str) -> bool {
+    declared == sniffed
+        || EXTENSION_GROUPS
+            .iter()
+            .any(|group| group.contains(&declared) && group.contains(&sniffed))
+}
+
+/// Characters which change the text direction or are invisible,
+/// they can be used to hide the real extension, like **invoice\u{202E}fdp.exe**.
+fn is_format_char(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}'
+    )
+}
+
+/// Cut filename to **MAX_FILENAME_LENGTH** characters, the extension is kept.
+fn truncate_name(name: &str) -> String {
+    if name.chars().count() <= MAX_FILENAME_LENGTH {
+        return name.to_string();
+    }
+
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.chars().count() <= 10 => {
+            (stem, format!(".{ext}"))
+        }
+        _ => (name, String::new()),
+    };
+
+    let stem_len = MAX_FILENAME_LENGTH - ext.chars().count();
+
+    format!("{}{ext}", stem.chars().take(stem_len).collect::<String>())
+}
+
+/// Normalize one filename from user input.
+///
+/// Path components, control and format characters are removed, characters which are not allowed in
+/// filenames are replaced, leading dots are removed and the length is limited.
+/// Non-ASCII names are kept, they are RFC 2231 encoded in the Content-Disposition header.
+pub fn sanitize_name(name: &str) -> String {
+    // browsers on Windows send sometimes the full path
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control() && !is_format_char(*c))
+        .collect();
+    let options = Options {
+        windows: true,
+        truncate: false,
+        replacement: "_",
+    };
+    let sanitized = sanitize_with_options(cleaned.trim(), options);
+    let sanitized = sanitized.trim_start_matches(['.', ' ']).trim_end();
+
+    if sanitized.is_empty() {
+        return "attachment".to_string();
+    }
+
+    truncate_name(sanitized)
+}
+
+/// Sanitize all attachment filenames and make them unique,
+/// duplicates get a number, like **file.pdf**, **file-2.pdf**.
+pub fn sanitize_names(files: &mut [(String, Vec<u8>)]) {
+    let mut used: Vec<String> = vec![];
+
+    for (name, _) in files.iter_mut() {
+        let clean = sanitize_name(name);
+        let mut unique = clean.clone();
+        let mut counter = 2;
+
+        while used.iter().any(|u| u.eq_ignore_ascii_case(&unique)) {
+            unique = match clean.rsplit_once('.') {
+                Some((stem, ext)) => format!("{stem}-{counter}.{ext}"),
+                None => format!("{clean}-{counter}"),
+            };
+            counter += 1;
+        }
+
+        if *name != unique {
+            debug!(
+                "Rename attachment \"{}\" to \"{unique}\"",
+                name.escape_debug()
+            );
+        }
+
+        used.push(unique.clone());
+        *name = unique;
+    }
+}
+
+/// Check attachments against the allow and deny lists from the recipient direction.
+///
+/// A file is rejected, when its sniffed mime type or its extension is not allowed, or
+/// when the sniffed content does not match the extension from the filename.
+pub fn check_types(
+    recipient: &Recipients,
+    files: &[(String, Vec<u8>)],
+) -> Result<(), ServiceError> {
+    for (name, data) in files {
+        let mime = mime_type(data);
+        let ext = extension(name);
+        let ext_str = ext.as_deref().unwrap_or_default();
+
+        let mime_allowed = (recipient.allowed_mime_types.is_empty()
+            || mime_matches(&recipient.allowed_mime_types, mime))
+            && !mime_matches(&recipient.denied_mime_types, mime);
+
+        let ext_allowed = (recipient.allowed_extensions.is_empty()
+            || recipient
+                .allowed_extensions
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(ext_str)))
+            && !recipient
+                .denied_extensions
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(ext_str));
+
+        let content_matches = match (infer::get(data), &ext) {
+            (Some(kind), Some(ext)) => extension_matches(ext, kind.extension()),
+            _ => true,
+        };
+
+        if !mime_allowed || !ext_allowed || !content_matches {
+            warn!(
+                "Reject attachment \"{name}\" for direction \"{}\", detected type: {mime}",
+                recipient.direction
+            );
+
+            return Err(ServiceError::UnprocessableEntity(format!(
+                "Attachment not allowed: {name}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Scan attachments with clamd, when **virus_scan** is enabled for the direction.
+///
+/// Infected files are rejected with their filename. When clamd is not reachable, the files are
+/// accepted with **fail_open** and rejected with **fail_closed**.
+pub async fn scan_files(
+    recipient: &Recipients,
+    files: &[(String, Vec<u8>)],
+) -> Result<(), ServiceError> {
+    if recipient.virus_scan == VirusScan::Off {
+        return Ok(());
+    }
+
+    for (name, data) in files {
+        match scan(data).await {
+            Ok(ScanResult::Clean) => {}
+            Ok(ScanResult::Infected(signature)) => {
+                warn!("Virus found in attachment \"{name}\": {signature}");
+
+                return Err(ServiceError::UnprocessableEntity(format!(
+                    "Attachment infected: {name}"
+                )));
+            }
+            Err(e) if recipient.virus_scan == VirusScan::FailOpen => {
+                warn!("Virus scan not possible, accept attachment \"{name}\": {e}");
+            }
+            Err(e) => {
+                error!("Virus scan not possible, reject attachment \"{name}\": {e}");
+
+                return Err(ServiceError::ServiceUnavailable(
+                    "Virus scan not available".to_string(),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_components_are_removed() {
+        assert_eq!(sanitize_name("C:\\Users\\me\\report.pdf"), "report.pdf");
+        assert_eq!(sanitize_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_name("/tmp/"), "attachment");
+    }
+
+    #[test]
+    fn hidden_and_invalid_characters_are_removed() {
+        assert_eq!(sanitize_name("invoice\u{202E}fdp.exe"), "invoicefdp.exe");
+        assert_eq!(sanitize_name("a\u{0}b\nc.txt"), "abc.txt");
+        assert_eq!(sanitize_name("what?.txt"), "what_.txt");
+        assert_eq!(sanitize_name("..hidden"), "hidden");
+        assert!(!sanitize_name("...").starts_with('.'));
+        assert_eq!(sanitize_name("Grüße.txt"), "Grüße.txt");
+    }
+
+    #[test]
+    fn long_names_keep_extension() {
+        let name = format!("{}.pdf", "a".repeat(150));
+        let sanitized = sanitize_name(&name);
+
+        assert_eq!(sanitized.chars().count(), MAX_FILENAME_LENGTH);
+        assert!(sanitized.ends_with("aaa.pdf"));
+    }
+
+    #[test]
+    fn duplicate_names_get_a_number() {
+        let mut files = vec![
+            ("file.pdf".to_string(), vec![]),
+            ("FILE.pdf".to_string(), vec![]),
+            ("dir/file.pdf".to_string(), vec![]),
+            ("notes".to_string(), vec![]),
+            ("notes".to_string(), vec![]),
+        ];
+
+        sanitize_names(&mut files);
+
+        let names: Vec<&str> = files.iter().map(|(n, _)| n.as_str()).collect();
+
+        assert_eq!(
+            names,
+            ["file.pdf", "FILE-2.pdf", "file-3.pdf", "notes", "notes-2"]
+        );
+    }
+
+    #[test]
+    fn mime_patterns_with_wildcard() {
+        let patterns = vec!["image/*".to_string(), "application/PDF".to_string()];
+
+        assert!(mime_matches(&patterns, "image/png"));
+        assert!(mime_matches(&patterns, "application/pdf"));
+        assert!(!mime_matches(&patterns, "application/zip"));
+    }
+
+    #[test]
+    fn extensions_from_same_type() {
+        assert!(extension_matches("jpeg", "jpg"));
+        assert!(extension_matches("docx", "zip"));
+        assert!(!extension_matches("exe", "pdf"));
+    }
+}
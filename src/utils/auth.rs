@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{http::header, HttpRequest};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::utils::config::{Auth, AuthMethod};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const TIMESTAMP_HEADER: &str = "X-Mailpeter-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Mailpeter-Signature";
+
+lazy_static! {
+    /// Signatures which are already used, with the time until they are valid
+    static ref SEEN_SIGNATURES: Mutex<HashMap<Vec<u8>, u64>> = Mutex::new(HashMap::new());
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn header_value<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Compare bearer token with all keys, in constant time.
+fn check_bearer(req: &HttpRequest, auth: &Auth) -> Result<(), String> {
+    let token = header_value(req, header::AUTHORIZATION.as_str())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(|| "missing bearer token".to_string())?;
+
+    let valid = auth
+        .keys
+        .iter()
+        .filter(|k| !k.is_empty())
+        .any(|k| bool::from(k.as_bytes().ct_eq(token.trim().as_bytes())));
+
+    if valid {
+        Ok(())
+    } else {
+        Err("invalid bearer token".to_string())
+    }
+}
+
+/// Verify signature over **{timestamp}.{body}** and remember it, so it can not be replayed.
+fn check_hmac(req: &HttpRequest, auth: &Auth, body: &[u8]) -> Result<(), String> {
+    let timestamp: u64 = header_value(req, TIMESTAMP_HEADER)
+        .ok_or_else(|| format!("missing {TIMESTAMP_HEADER} header"))?
+        .parse()
+        .map_err(|_| "malformed timestamp".to_string())?;
+    let signature = header_value(req, SIGNATURE_HEADER)
+        .ok_or_else(|| format!("missing {SIGNATURE_HEADER} header"))?;
+    let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let signature = hex::decode(signature).map_err(|_| "malformed signature".to_string())?;
+    let current = now();
+
+    if current.abs_diff(timestamp) > auth.max_age_seconds {
+        return Err(format!("timestamp {timestamp} out of range"));
+    }
+
+    let valid = auth.keys.iter().filter(|k| !k.is_empty()).any(|key| {
+        let mut mac =
+            HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC can take key of any size");
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+
+        mac.verify_slice(&signature).is_ok()
+    });
+
+    if !valid {
+        return Err("invalid signature".to_string());
+    }
+
+    let mut seen = SEEN_SIGNATURES.lock().unwrap();
+
+    // expired signatures are rejected anyway, no need to remember them
+    seen.retain(|_, expires| *expires >= current);
+
+    if seen
+        .insert(signature, timestamp + auth.max_age_seconds)
+        .is_some()
+    {
+        return Err("signature already used".to_string());
+    }
+
+    Ok(())
+}
+
+/// Check the credentials from the request. Returns the reason, when the request is not authorized.
+pub fn authorize(req: &HttpRequest, auth: &Auth, body: &[u8]) -> Result<(), String> {
+    match auth.method {
+        AuthMethod::Bearer => check_bearer(req, auth),
+        AuthMethod::Hmac => check_hmac(req, auth, body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn auth(method: AuthMethod) -> Auth {
+        Auth {
+            method,
+            keys: vec![String::new(), "old-key".to_string(), "new-key".to_string()],
+            max_age_seconds: 300,
+        }
+    }
+
+    fn sign(key: &str, timestamp: u64, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn signed_request(timestamp: u64, signature: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+            .insert_header((SIGNATURE_HEADER, signature))
+            .to_http_request()
+    }
+
+    #[test]
+    fn bearer_with_any_key() {
+        let auth = auth(AuthMethod::Bearer);
+        let request = |value: &str| {
+            TestRequest::default()
+                .insert_header((header::AUTHORIZATION, value))
+                .to_http_request()
+        };
+
+        assert!(authorize(&request("Bearer new-key"), &auth, b"").is_ok());
+        assert!(authorize(&request("Bearer old-key"), &auth, b"").is_ok());
+        assert!(authorize(&request("Bearer "), &auth, b"").is_err());
+        assert!(authorize(&request("Bearer wrong"), &auth, b"").is_err());
+        assert!(authorize(&request("new-key"), &auth, b"").is_err());
+        assert!(authorize(&TestRequest::default().to_http_request(), &auth, b"").is_err());
+    }
+
+    #[test]
+    fn hmac_signature_only_once() {
+        let auth = auth(AuthMethod::Hmac);
+        let body = br#"{"mail":"a@example.org","text":"once"}"#;
+        let timestamp = now();
+        let req = signed_request(timestamp, &sign("new-key", timestamp, body));
+
+        assert_eq!(authorize(&req, &auth, body), Ok(()));
+        assert_eq!(
+            authorize(&req, &auth, body),
+            Err("signature already used".to_string())
+        );
+    }
+
+    #[test]
+    fn hmac_over_timestamp_and_body() {
+        let auth = auth(AuthMethod::Hmac);
+        let body = br#"{"mail":"a@example.org","text":"changed"}"#;
+        let timestamp = now();
+        let signature = sign("old-key", timestamp, body);
+
+        assert_eq!(
+            authorize(&signed_request(timestamp, &signature), &auth, b"other body"),
+            Err("invalid signature".to_string())
+        );
+        assert_eq!(
+            authorize(&signed_request(timestamp - 1, &signature), &auth, body),
+            Err("invalid signature".to_string())
+        );
+        assert_eq!(
+            authorize(
+                &signed_request(timestamp, &sign("unknown", timestamp, body)),
+                &auth,
+                body
+            ),
+            Err("invalid signature".to_string())
+        );
+    }
+
+    #[test]
+    fn hmac_timestamp_out_of_range() {
+        let auth = auth(AuthMethod::Hmac);
+        let body = b"late";
+        let timestamp = now() - 301;
+        let req = signed_request(timestamp, &sign("new-key", timestamp, body));
+
+        assert!(authorize(&req, &auth, body)
+            .unwrap_err()
+            .ends_with("out of range"));
+    }
+
+    #[test]
+    fn hmac_missing_headers() {
+        let auth = auth(AuthMethod::Hmac);
+        let req = TestRequest::default()
+            .insert_header((TIMESTAMP_HEADER, "abc"))
+            .to_http_request();
+
+        assert!(authorize(&TestRequest::default().to_http_request(), &auth, b"").is_err());
+        assert_eq!(
+            authorize(&req, &auth, b""),
+            Err("malformed timestamp".to_string())
+        );
+    }
+}
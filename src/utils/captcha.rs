@@ -0,0 +1,305 @@
+use std::{net::IpAddr, time::Duration};
+
+use lazy_static::lazy_static;
+use log::{error, warn};
+use serde::Deserialize;
+
+use crate::utils::{
+    config::{Captcha, CaptchaProvider},
+    errors::ServiceError,
+};
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("HTTP client");
+}
+
+/// Answer from the verify endpoint, hCaptcha, Turnstile and reCAPTCHA use the same format.
+#[derive(Debug, Deserialize)]
+struct VerifyResponse {
+    success: bool,
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+    /// Only from reCAPTCHA v3, hCaptcha Enterprise has a risk score with the opposite meaning.
+    score: Option<f64>,
+}
+
+/// Verify the response token from the CAPTCHA widget at the provider.
+///
+/// Invalid tokens get an **UnprocessableEntity** error, when the provider is not reachable
+/// the message is rejected with **ServiceUnavailable**.
+pub async fn verify(
+    captcha: &Captcha,
+    response: &str,
+    ip: Option<IpAddr>,
+) -> Result<(), ServiceError> {
+    check(&CLIENT, captcha, response, ip).await
+}
+
+async fn check(
+    client: &reqwest::Client,
+    captcha: &Captcha,
+    response: &str,
+    ip: Option<IpAddr>,
+) -> Result<(), ServiceError> {
+    if response.is_empty() {
+        return Err(ServiceError::UnprocessableEntity(
+            "CAPTCHA verification failed".to_string(),
+        ));
+    }
+
+    let mut form = vec![
+        ("secret", captcha.secret.clone()),
+        ("response", response.to_string()),
+    ];
+
+    if let Some(ip) = ip {
+        form.push(("remoteip", ip.to_string()));
+    }
+
+    let result = match client.post(captcha.verify_url()).form(&form).send().await {
+        Ok(res) => res.json::<VerifyResponse>().await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(VerifyResponse {
+            success: true,
+            score: Some(score),
+            ..
+        }) if captcha.provider == CaptchaProvider::Recaptcha && score < captcha.min_score => {
+            warn!("CAPTCHA score too low: {score} < {}", captcha.min_score);
+
+            Err(ServiceError::UnprocessableEntity(
+                "CAPTCHA verification failed".to_string(),
+            ))
+        }
+        Ok(VerifyResponse { success: true, .. }) => Ok(()),
+        Ok(VerifyResponse { error_codes, .. }) => {
+            warn!("CAPTCHA verification failed: {error_codes:?}");
+
+            Err(ServiceError::UnprocessableEntity(
+                "CAPTCHA verification failed".to_string(),
+            ))
+        }
+        Err(e) => {
+            error!("CAPTCHA verification not available: {e}");
+
+            Err(ServiceError::ServiceUnavailable(
+                "CAPTCHA verification not available".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    fn captcha(provider: CaptchaProvider, verify_url: String) -> Captcha {
+        Captcha {
+            provider,
+            secret: "secret".to_string(),
+            verify_url,
+            min_score: 0.5,
+        }
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap()
+    }
+
+    /// Answer one request like the provider with **body**, returns the form data from the request.
+    async fn provider(body: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/siteverify", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+
+            // read until the form data from the content length is complete
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+
+                if let Some((head, form)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| {
+                            l.to_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(str::to_string)
+                        })
+                        .and_then(|l| l.trim().parse::<usize>().ok())
+                        .unwrap_or_default();
+
+                    if form.len() >= length {
+                        let answer = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                            body.len()
+                        );
+                        stream.write_all(answer.as_bytes()).await.unwrap();
+
+                        return form.to_string();
+                    }
+                }
+            }
+        });
+
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn success() {
+        let (url, server) = provider(r#"{"success": true}"#).await;
+        let ip = "192.0.2.1".parse().ok();
+
+        check(
+            &client(),
+            &captcha(CaptchaProvider::Turnstile, url),
+            "token",
+            ip,
+        )
+        .await
+        .unwrap();
+
+        let form = server.await.unwrap();
+        assert!(form.contains("secret=secret"));
+        assert!(form.contains("response=token"));
+        assert!(form.contains("remoteip=192.0.2.1"));
+    }
+
+    #[tokio::test]
+    async fn failure() {
+        let (url, _server) =
+            provider(r#"{"success": false, "error-codes": ["invalid-input-response"]}"#).await;
+        let result = check(
+            &client(),
+            &captcha(CaptchaProvider::Hcaptcha, url),
+            "token",
+            None,
+        )
+        .await;
+
+        assert!(matches!(result, Err(ServiceError::UnprocessableEntity(_))));
+    }
+
+    #[tokio::test]
+    async fn empty_response() {
+        // no request is sent without token
+        let captcha = captcha(
+            CaptchaProvider::Turnstile,
+            "http://127.0.0.1:9/".to_string(),
+        );
+        let result = check(&client(), &captcha, "", None).await;
+
+        assert!(matches!(result, Err(ServiceError::UnprocessableEntity(_))));
+    }
+
+    #[tokio::test]
+    async fn low_score() {
+        let (url, _server) = provider(r#"{"success": true, "score": 0.3}"#).await;
+        let result = check(
+            &client(),
+            &captcha(CaptchaProvider::Recaptcha, url),
+            "token",
+            None,
+        )
+        .await;
+
+        assert!(matches!(result, Err(ServiceError::UnprocessableEntity(_))));
+
+        let (url, _server) = provider(r#"{"success": true, "score": 0.9}"#).await;
+        let result = check(
+            &client(),
+            &captcha(CaptchaProvider::Recaptcha, url),
+            "token",
+            None,
+        )
+        .await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn score_only_from_recaptcha() {
+        // hCaptcha Enterprise sends a risk score, a low value is good there
+        let (url, _server) = provider(r#"{"success": true, "score": 0.1}"#).await;
+        let result = check(
+            &client(),
+            &captcha(CaptchaProvider::Hcaptcha, url),
+            "token",
+            None,
+        )
+        .await;
+
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        // accept the connection, but never answer
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/siteverify", listener.local_addr().unwrap());
+        let _server = tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let result = check(
+            &client(),
+            &captcha(CaptchaProvider::Turnstile, url),
+            "token",
+            None,
+        )
+        .await;
+
+        assert!(matches!(result, Err(ServiceError::ServiceUnavailable(_))));
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        // bind a free port and close it again, so nothing listens there
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/siteverify", listener.local_addr().unwrap());
+        drop(listener);
+
+        let result = check(
+            &client(),
+            &captcha(CaptchaProvider::Turnstile, url),
+            "token",
+            None,
+        )
+        .await;
+
+        assert!(matches!(result, Err(ServiceError::ServiceUnavailable(_))));
+    }
+
+    #[tokio::test]
+    async fn invalid_answer() {
+        let (url, _server) = provider("<html>Bad Gateway</html>").await;
+        let result = check(
+            &client(),
+            &captcha(CaptchaProvider::Turnstile, url),
+            "token",
+            None,
+        )
+        .await;
+
+        assert!(matches!(result, Err(ServiceError::ServiceUnavailable(_))));
+    }
+}
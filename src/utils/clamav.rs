@@ -0,0 +1,168 @@
+use std::{io, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::CONFIG;
+
+/// Size from the chunks, which are streamed to clamd
+const CHUNK_SIZE: usize = 65536;
+
+/// Result from a virus scan
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScanResult {
+    Clean,
+    Infected(String),
+}
+
+/// Stream data with the INSTREAM command to clamd and read the answer.
+///
+/// The data is sent in chunks, each chunk is prefixed with its length as 4 byte big endian number,
+/// a chunk with length zero ends the stream. clamd answers with **stream: OK** or with
+/// **stream: {signature} FOUND**.
+async fn instream<S>(mut stream: S, data: &[u8]) -> io::Result<ScanResult>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    for chunk in data.chunks(CHUNK_SIZE) {
+        stream
+            .write_all(&(chunk.len() as u32).to_be_bytes())
+            .await?;
+        stream.write_all(chunk).await?;
+    }
+
+    stream.write_all(&[0; 4]).await?;
+    stream.flush().await?;
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+
+    let response = String::from_utf8_lossy(&response);
+    let response = response.trim_end_matches(['\0', '\n']);
+
+    if response.ends_with("OK") {
+        Ok(ScanResult::Clean)
+    } else if let Some(found) = response.strip_suffix(" FOUND") {
+        let signature = found.rsplit_once(": ").map_or(found, |(_, s)| s);
+
+        Ok(ScanResult::Infected(signature.to_string()))
+    } else {
+        Err(io::Error::other(format!("clamd error: {response}")))
+    }
+}
+
+/// Scan data with clamd.
+///
+/// The **socket** from the clamav config is a path to a unix socket, like **/run/clamav/clamd.ctl**,
+/// or a TCP address, like **127.0.0.1:3310**.
+pub async fn scan(data: &[u8]) -> io::Result<ScanResult> {
+    let socket = &CONFIG.clamav.socket;
+    let duration = Duration::from_secs(CONFIG.clamav.timeout_seconds);
+
+    // the timeout covers also the connect, a hanging clamd should not block the delivery
+    let result = if socket.starts_with('/') {
+        #[cfg(unix)]
+        {
+            timeout(duration, async {
+                let stream = tokio::net::UnixStream::connect(socket).await?;
+                instream(stream, data).await
+            })
+            .await
+        }
+
+        #[cfg(not(unix))]
+        {
+            return Err(io::Error::other("Unix sockets are not supported"));
+        }
+    } else {
+        timeout(duration, async {
+            let stream = TcpStream::connect(socket).await?;
+            instream(stream, data).await
+        })
+        .await
+    };
+
+    result.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "clamd timeout"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::duplex;
+
+    /// Read the INSTREAM command and the chunks like clamd, answer with **response**.
+    async fn clamd<S>(mut stream: S, response: &[u8]) -> Vec<u8>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut command = [0; 10];
+        stream.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"zINSTREAM\0");
+
+        let mut data = vec![];
+
+        loop {
+            let mut length = [0; 4];
+            stream.read_exact(&mut length).await.unwrap();
+            let length = u32::from_be_bytes(length) as usize;
+
+            if length == 0 {
+                break;
+            }
+
+            assert!(length <= CHUNK_SIZE);
+
+            let mut chunk = vec![0; length];
+            stream.read_exact(&mut chunk).await.unwrap();
+            data.extend(chunk);
+        }
+
+        stream.write_all(response).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        data
+    }
+
+    #[tokio::test]
+    async fn clean_data_in_chunks() {
+        let data = vec![7; CHUNK_SIZE * 2 + 10];
+        let (client, server) = duplex(1024);
+        let server = tokio::spawn(async move { clamd(server, b"stream: OK\0").await });
+
+        assert_eq!(instream(client, &data).await.unwrap(), ScanResult::Clean);
+        assert_eq!(server.await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn infected_data() {
+        let (client, server) = duplex(1024);
+        let server =
+            tokio::spawn(
+                async move { clamd(server, b"stream: Eicar-Test-Signature FOUND\0").await },
+            );
+
+        assert_eq!(
+            instream(client, b"X5O!P%@AP").await.unwrap(),
+            ScanResult::Infected("Eicar-Test-Signature".to_string())
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn clamd_error() {
+        let (client, server) = duplex(1024);
+        let server =
+            tokio::spawn(
+                async move { clamd(server, b"INSTREAM size limit exceeded. ERROR\0").await },
+            );
+
+        assert!(instream(client, b"data").await.is_err());
+        server.await.unwrap();
+    }
+}
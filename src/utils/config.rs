@@ -1,10 +1,32 @@
-use std::{fs, path::Path};
+use std::{fs, ops::Deref, path::Path, sync::RwLock};
 
+use lettre::{
+    message::{dkim::DkimConfig, Mailbox},
+    Address,
+};
 use log::{debug, LevelFilter};
+use minijinja::Environment;
+use regex::Regex;
 use serde::{de, Deserialize, Deserializer};
 use toml;
 
-use crate::utils::errors::ServiceError;
+use crate::utils::{
+    dkim::compile_signers,
+    errors::ServiceError,
+    pgp::{key_mails, missing_keys},
+    smime::{load_keys, missing_certificates, SmimeKeys},
+    spam::{compile_rules, CompiledRule},
+    templates::compile_templates,
+};
+
+/// Name from the relay, which is configured in the mail section
+pub const DEFAULT_RELAY: &str = "default";
+
+/// Form fields with a fixed meaning, they can not be used as custom fields
+pub const RESERVED_FIELDS: [&str; 6] = ["mail", "subject", "text", "token", "fields", "attachment"];
+
+/// Maximum number of characters from custom fields, without own **max_length**
+pub const FIELD_MAX_LENGTH: usize = 1000;
 
 /// Config structs
 
@@ -19,21 +41,370 @@ pub struct Config {
     pub reverse_proxy_ip: String,
     pub limit_request_seconds: u64,
     pub max_attachment_size_mb: f64,
+    #[serde(default)]
+    pub max_file_size_mb: f64,
+    #[serde(default = "default_max_attachments")]
+    pub max_attachments: usize,
+    #[serde(default = "default_max_json_size_kb")]
+    pub max_json_size_kb: usize,
+    #[serde(default = "default_max_form_fields")]
+    pub max_form_fields: usize,
     pub routes: Vec<String>,
+    #[serde(default)]
+    pub async_response: bool,
     pub mail_archive: String,
+    #[serde(default)]
+    pub queue: Queue,
+    #[serde(default)]
+    pub relays: Vec<Relay>,
+    #[serde(default)]
+    pub clamav: ClamAv,
+    #[serde(default)]
+    pub form_secret: String,
+    #[serde(default)]
+    pub cors: Cors,
+    #[serde(default)]
+    pub dkim: Vec<Dkim>,
     pub mail: Mail,
+    #[serde(skip)]
+    pub spam_rules: Vec<CompiledRule>,
+    #[serde(skip)]
+    pub form_key: Vec<u8>,
+    #[serde(skip)]
+    pub template_env: Environment<'static>,
+    #[serde(skip)]
+    pub dkim_signers: Vec<DkimConfig>,
+}
+
+impl Config {
+    /// Find recipient entry by direction.
+    pub fn recipient(&self, direction: &str) -> Option<&Recipients> {
+        self.mail
+            .recipients
+            .iter()
+            .find(|r| r.direction == direction)
+    }
+
+    /// CORS settings for the direction, falls back to the global settings.
+    /// Unknown directions use the settings from the default direction.
+    pub fn cors(&self, direction: &str) -> &Cors {
+        self.recipient(direction)
+            .or_else(|| self.recipient(&self.mail.default_direction))
+            .and_then(|r| r.cors.as_ref())
+            .unwrap_or(&self.cors)
+    }
+
+    /// Check if the address, or its domain with leading **@**, is on the suppression list.
+    pub fn suppressed(&self, mail: &str) -> bool {
+        let domain = mail.rsplit_once('@').map(|(_, d)| d).unwrap_or_default();
+
+        self.mail.suppression_list.iter().any(|entry| {
+            entry.eq_ignore_ascii_case(mail)
+                || entry
+                    .strip_prefix('@')
+                    .is_some_and(|d| d.eq_ignore_ascii_case(domain))
+        })
+    }
+
+    /// Maximum size from all attachments together in bytes.
+    pub fn max_attachment_bytes(&self) -> usize {
+        (self.max_attachment_size_mb * 1048576.0) as usize
+    }
+
+    /// Maximum size from one attachment in bytes, falls back to the total size.
+    pub fn max_file_bytes(&self) -> usize {
+        if self.max_file_size_mb > 0.0 {
+            (self.max_file_size_mb * 1048576.0) as usize
+        } else {
+            self.max_attachment_bytes()
+        }
+    }
+
+    /// Maximum size from JSON requests, and from all form fields of multipart requests, in bytes.
+    pub fn max_json_bytes(&self) -> usize {
+        self.max_json_size_kb * 1024
+    }
+
+    /// Find relay by name, the relay from the mail section has the name **default**.
+    pub fn relay(&self, name: &str) -> Option<&Relay> {
+        std::iter::once(&self.mail.relay)
+            .chain(self.relays.iter())
+            .find(|r| r.name == name)
+    }
+
+    /// Sender address from the primary relay of a message.
+    pub fn sender(&self, relays: &[String]) -> &str {
+        relays
+            .first()
+            .and_then(|name| self.relay(name))
+            .unwrap_or(&self.mail.relay)
+            .sender()
+    }
+
+    /// All configured relays, starting with the default relay.
+    pub fn all_relays(&self) -> impl Iterator<Item = &Relay> {
+        std::iter::once(&self.mail.relay).chain(self.relays.iter())
+    }
+
+    /// Warnings for settings from older versions, which are still read.
+    pub fn deprecations(&self) -> Vec<String> {
+        self.all_relays()
+            .filter(|r| r.starttls.is_some())
+            .map(|r| {
+                format!(
+                    "Relay {}: \"starttls\" is deprecated, use security = \"{}\" instead",
+                    r.name,
+                    r.security().name()
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Queue {
+    pub spool_dir: String,
+    pub max_attempts: u32,
+    pub retry_base_seconds: u64,
+    pub retry_max_seconds: u64,
+    pub status_retention_hours: u64,
+    pub status_errors: bool,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            spool_dir: "/var/spool/mailpeter".to_string(),
+            max_attempts: 8,
+            retry_base_seconds: 30,
+            retry_max_seconds: 3600,
+            status_retention_hours: 24,
+            status_errors: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ClamAv {
+    pub socket: String,
+    pub timeout_seconds: u64,
+}
+
+impl Default for ClamAv {
+    fn default() -> Self {
+        Self {
+            socket: "/run/clamav/clamd.ctl".to_string(),
+            timeout_seconds: 30,
+        }
+    }
+}
+
+/// CORS settings
+///
+/// Leave **allowed_origins** empty to disable CORS, use **"*"** to allow all origins.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Cors {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age_seconds: u64,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string(), "PUT".to_string()],
+            allowed_headers: vec!["Content-Type".to_string()],
+            max_age_seconds: 3600,
+        }
+    }
+}
+
+impl Cors {
+    pub fn enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|o| o == "*" || o.eq_ignore_ascii_case(origin))
+    }
+}
+
+/// DKIM key, which signs all mails with a From address in **domain**.
+/// Keys with **directions** sign only mails from these directions.
+#[derive(Debug, Deserialize)]
+pub struct Dkim {
+    pub domain: String,
+    pub selector: String,
+    pub private_key: String,
+    #[serde(default)]
+    pub algorithm: DkimAlgorithm,
+    #[serde(default = "default_dkim_headers")]
+    pub headers: Vec<String>,
+    #[serde(default = "default_dkim_canonicalization")]
+    pub canonicalization: String,
+    #[serde(default)]
+    pub directions: Vec<String>,
+}
+
+/// Type from the DKIM private key
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DkimAlgorithm {
+    #[default]
+    Rsa,
+    Ed25519,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Mail {
+    #[serde(flatten)]
+    pub relay: Relay,
+    pub alias: String,
+    #[serde(default)]
+    pub default_direction: String,
+    #[serde(default)]
+    pub block_words: Vec<String>,
+    #[serde(default)]
+    pub spam_rules: Vec<SpamRule>,
+    #[serde(default)]
+    pub suppression_list: Vec<String>,
+    #[serde(default)]
+    pub smime: Option<Smime>,
+    pub recipients: Vec<Recipients>,
+}
+
+/// Spam rule, the pattern is a regex which is checked against the targets.
+/// When it matches, the weight is added to the spam score from the message.
+#[derive(Debug, Deserialize)]
+pub struct SpamRule {
+    pub pattern: String,
+    pub weight: f64,
+    #[serde(default = "default_spam_targets")]
+    pub targets: Vec<SpamTarget>,
+}
+
+/// Parts of the message, which are checked from a spam rule
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamTarget {
+    Subject,
+    Body,
+    Sender,
+    AttachmentName,
+}
+
+/// SMTP relay
+///
+/// The SMTP settings from the mail section are the **default** relay,
+/// more relays can be added with **[[relays]]**. Mails through the relay are sent **from**
+/// this address, or from the **user**, when it is empty.
+///
+/// Configs from older versions have **starttls** instead of **security**, it is still read,
+/// when **security** is missing.
+#[derive(Debug, Deserialize)]
+pub struct Relay {
+    #[serde(default)]
+    pub name: String,
     pub smtp: String,
     pub port: u16,
+    #[serde(default)]
+    security: Option<Security>,
+    #[serde(default)]
+    pub starttls: Option<bool>,
+    #[serde(default)]
+    pub ca_bundle: String,
+    #[serde(default)]
+    pub skip_verify: bool,
     pub user: String,
     pub password: String,
-    pub starttls: bool,
-    pub alias: String,
-    pub block_words: Vec<String>,
-    pub recipients: Vec<Recipients>,
+    #[serde(default)]
+    pub from: String,
+    #[serde(default)]
+    pub pool: Pool,
+}
+
+impl Relay {
+    /// Security from the config, **starttls = false** was implicit TLS before.
+    /// Relays without both settings use STARTTLS.
+    pub fn security(&self) -> Security {
+        match (self.security, self.starttls) {
+            (Some(security), _) => security,
+            (None, Some(false)) => Security::Tls,
+            (None, _) => Security::Starttls,
+        }
+    }
+
+    /// Sender address, providers reject mails from other addresses than the account.
+    pub fn sender(&self) -> &str {
+        if self.from.is_empty() {
+            &self.user
+        } else {
+            &self.from
+        }
+    }
+}
+
+/// Connection pool for the SMTP transport
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Pool {
+    pub max_size: u32,
+    pub min_idle: u32,
+    pub idle_timeout_seconds: u64,
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            min_idle: 0,
+            idle_timeout_seconds: 60,
+        }
+    }
+}
+
+/// Connection security to the SMTP server
+///
+/// * **tls** - Implicit TLS, the connection is encrypted from the start, mostly on port 465
+/// * **starttls** - Plain connection which is upgraded with STARTTLS, mostly on port 587
+/// * **none** - No encryption, only for local or internal relays, mostly on port 25
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    Tls,
+    Starttls,
+    None,
+}
+
+impl Security {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Tls => "tls",
+            Self::Starttls => "starttls",
+            Self::None => "none",
+        }
+    }
+}
+
+/// Virus scan for attachments
+///
+/// * **off** - Attachments are not scanned
+/// * **fail_open** - Attachments are scanned, when clamd is not reachable they are accepted
+/// * **fail_closed** - Attachments are scanned, when clamd is not reachable the request is rejected
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VirusScan {
+    #[default]
+    Off,
+    FailOpen,
+    FailClosed,
 }
 
 #[derive(Debug, Deserialize)]
@@ -41,6 +412,54 @@ pub struct Recipients {
     pub allow_html: bool,
     pub direction: String,
     pub mails: Vec<String>,
+    #[serde(default)]
+    pub relay: String,
+    #[serde(default)]
+    pub fallback_relays: Vec<String>,
+    #[serde(default)]
+    pub allowed_mime_types: Vec<String>,
+    #[serde(default)]
+    pub denied_mime_types: Vec<String>,
+    #[serde(default)]
+    pub allowed_extensions: Vec<String>,
+    #[serde(default)]
+    pub denied_extensions: Vec<String>,
+    #[serde(default)]
+    pub virus_scan: VirusScan,
+    #[serde(default = "default_spam_reject_score")]
+    pub spam_reject_score: f64,
+    #[serde(default)]
+    pub spam_quarantine_score: f64,
+    #[serde(default)]
+    pub honeypot: String,
+    #[serde(default)]
+    pub form_token: bool,
+    #[serde(default = "default_min_fill_seconds")]
+    pub min_fill_seconds: u64,
+    #[serde(default = "default_max_fill_minutes")]
+    pub max_fill_minutes: u64,
+    #[serde(default)]
+    pub captcha: Option<Captcha>,
+    #[serde(default)]
+    pub auth: Option<Auth>,
+    #[serde(default)]
+    pub cors: Option<Cors>,
+    #[serde(default)]
+    pub pgp: Option<Pgp>,
+    #[serde(default)]
+    pub smime: Option<Smime>,
+    #[serde(default)]
+    pub fields: Vec<FormField>,
+    #[serde(default)]
+    pub accept_unknown_fields: bool,
+    #[serde(default)]
+    pub templates: Templates,
+    #[serde(default)]
+    pub confirmation: Confirmation,
+    #[serde(default)]
+    pub success_redirect: String,
+    #[serde(default)]
+    pub error_redirect: String,
     pub send_copy: bool,
     #[serde(skip_deserializing)]
     pub subject: String,
@@ -48,6 +467,278 @@ pub struct Recipients {
     pub message: String,
 }
 
+impl Recipients {
+    /// Relays to try in order, the primary relay followed by the fallback relays.
+    pub fn relays(&self) -> Vec<String> {
+        let primary = if self.relay.is_empty() {
+            DEFAULT_RELAY.to_string()
+        } else {
+            self.relay.clone()
+        };
+
+        std::iter::once(primary)
+            .chain(self.fallback_relays.iter().cloned())
+            .collect()
+    }
+
+    /// Form fields which are accepted additionally to mail, subject and text,
+    /// like the honeypot field and the response field from the CAPTCHA.
+    pub fn extra_fields(&self) -> Vec<&str> {
+        let mut fields = vec![];
+
+        if !self.honeypot.is_empty() {
+            fields.push(self.honeypot.as_str());
+        }
+
+        if let Some(captcha) = &self.captcha {
+            fields.push(captcha.provider.field());
+        }
+
+        fields
+    }
+}
+
+/// Template files for the mail to the staff, empty paths are not used
+///
+/// * **subject** - Template for the subject, only the first line is used
+/// * **text** - Template for a plain text body
+/// * **html** - Template for a html body, values are escaped automatically
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Templates {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Confirmation to the user, which is sent with **send_copy** instead of a copy from the staff mail
+///
+/// * **from** - Sender from the confirmation, default is the relay user
+/// * **reply_to** - Reply-To from the confirmation, empty for no Reply-To
+/// * **subject** - Template for the subject, only the first line is used
+/// * **text** - Template for a plain text body
+/// * **html** - Template for a html body, values are escaped automatically
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Confirmation {
+    pub from: String,
+    pub reply_to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Custom form field, which is rendered into the mail
+///
+/// The **label** is used in the mail, it falls back to the **name**. Values are checked against
+/// **max_length**, the **format** and the optional regex **pattern**.
+#[derive(Debug, Deserialize)]
+pub struct FormField {
+    pub name: String,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default = "default_field_max_length")]
+    pub max_length: usize,
+    #[serde(default)]
+    pub format: FieldFormat,
+    #[serde(default)]
+    pub pattern: String,
+    #[serde(skip)]
+    pub regex: Option<Regex>,
+}
+
+impl FormField {
+    pub fn label(&self) -> &str {
+        if self.label.is_empty() {
+            &self.name
+        } else {
+            &self.label
+        }
+    }
+}
+
+/// Format from a custom form field
+///
+/// * **text** - Any text
+/// * **email** - A valid mail address
+/// * **phone** - A phone number, with digits, spaces and **+()./-**
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldFormat {
+    #[default]
+    Text,
+    Email,
+    Phone,
+}
+
+/// OpenPGP encryption for the mails to the staff of a direction
+///
+/// * **keys** - Armored public key files, all keys are used for encryption
+/// * **keyring** - GnuPG home directory with the public keys, used instead of the key files
+/// * **gpg** - The gpg binary
+#[derive(Debug, Deserialize)]
+pub struct Pgp {
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub keyring: String,
+    #[serde(default = "default_gpg")]
+    pub gpg: String,
+    #[serde(skip)]
+    pub key_mails: Vec<String>,
+}
+
+/// S/MIME signing and encryption, for a direction or for mails from command line
+///
+/// * **certificate** - Signing certificate as PEM, with optional chain, or PKCS#12 file with certificate and key
+/// * **private_key** - Private key as PEM, leave it empty for PKCS#12
+/// * **password** - Password from the PKCS#12 file
+/// * **recipient_certificates** - PEM certificates from the recipients, mails are encrypted when this is set
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Smime {
+    pub certificate: String,
+    pub private_key: String,
+    pub password: String,
+    pub recipient_certificates: Vec<String>,
+    #[serde(skip)]
+    pub keys: SmimeKeys,
+}
+
+/// Authentication for a direction
+///
+/// With **bearer** the client sends one of the **keys** in the header **Authorization: Bearer {key}**.
+/// With **hmac** the client signs **{timestamp}.{body}** with one of the keys and sends the headers
+/// **X-Mailpeter-Timestamp** and **X-Mailpeter-Signature**. Signatures older then **max_age_seconds**,
+/// or which are used twice, are rejected.
+#[derive(Debug, Deserialize)]
+pub struct Auth {
+    pub method: AuthMethod,
+    pub keys: Vec<String>,
+    #[serde(default = "default_auth_max_age_seconds")]
+    pub max_age_seconds: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    Bearer,
+    Hmac,
+}
+
+/// CAPTCHA verification for a direction
+///
+/// The response token from the form is verified server-side, with the **secret** from the provider.
+/// Leave **verify_url** empty to use the URL from the provider. Answers from reCAPTCHA v3 have a score,
+/// responses below **min_score** are rejected.
+#[derive(Debug, Deserialize)]
+pub struct Captcha {
+    pub provider: CaptchaProvider,
+    pub secret: String,
+    #[serde(default)]
+    pub verify_url: String,
+    #[serde(default = "default_min_score")]
+    pub min_score: f64,
+}
+
+impl Captcha {
+    pub fn verify_url(&self) -> &str {
+        if self.verify_url.is_empty() {
+            self.provider.verify_url()
+        } else {
+            &self.verify_url
+        }
+    }
+}
+
+/// CAPTCHA provider
+///
+/// * **hcaptcha** - hCaptcha, the form sends the field **h-captcha-response**
+/// * **turnstile** - Cloudflare Turnstile, the form sends the field **cf-turnstile-response**
+/// * **recaptcha** - Google reCAPTCHA, the form sends the field **g-recaptcha-response**
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    Hcaptcha,
+    Turnstile,
+    Recaptcha,
+}
+
+impl CaptchaProvider {
+    /// Form field, which contains the response token from the widget.
+    pub fn field(&self) -> &'static str {
+        match self {
+            Self::Hcaptcha => "h-captcha-response",
+            Self::Turnstile => "cf-turnstile-response",
+            Self::Recaptcha => "g-recaptcha-response",
+        }
+    }
+
+    fn verify_url(&self) -> &'static str {
+        match self {
+            Self::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+            Self::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            Self::Recaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        }
+    }
+}
+
+fn default_spam_targets() -> Vec<SpamTarget> {
+    vec![SpamTarget::Subject, SpamTarget::Body]
+}
+
+fn default_min_score() -> f64 {
+    0.5
+}
+
+fn default_gpg() -> String {
+    "gpg".to_string()
+}
+
+fn default_dkim_headers() -> Vec<String> {
+    ["From", "To", "Subject", "Date", "Reply-To", "MIME-Version"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_dkim_canonicalization() -> String {
+    "relaxed/relaxed".to_string()
+}
+
+fn default_spam_reject_score() -> f64 {
+    10.0
+}
+
+fn default_min_fill_seconds() -> u64 {
+    3
+}
+
+fn default_max_fill_minutes() -> u64 {
+    60
+}
+
+fn default_field_max_length() -> usize {
+    FIELD_MAX_LENGTH
+}
+
+fn default_auth_max_age_seconds() -> u64 {
+    300
+}
+
+fn default_max_attachments() -> usize {
+    10
+}
+
+fn default_max_json_size_kb() -> usize {
+    64
+}
+
+fn default_max_form_fields() -> usize {
+    50
+}
+
 /// Deserialize log level from string
 pub fn string_to_log_level<'de, D>(deserializer: D) -> Result<LevelFilter, D::Error>
 where
@@ -84,12 +775,266 @@ pub fn config_path(path: &Option<String>) -> String {
 }
 
 /// read config from file
+/// Config which can be replaced while mailpeter runs.
+///
+/// Every config is leaked, so references from requests, which are still running, stay valid
+/// after a reload. A config is only a few kilobytes and reloads are rare.
+pub struct SharedConfig(RwLock<&'static Config>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        Self(RwLock::new(Box::leak(Box::new(config))))
+    }
+
+    /// Use the new config for all following requests and deliveries.
+    pub fn replace(&self, config: Config) {
+        if let Ok(mut current) = self.0.write() {
+            *current = Box::leak(Box::new(config));
+        }
+    }
+}
+
+impl Deref for SharedConfig {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        match self.0.read() {
+            Ok(current) => *current,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+}
+
 pub fn read_config(path: &Option<String>) -> Result<Config, ServiceError> {
     let config_file = config_path(path);
     debug!("Read config from: {}", config_file);
 
     let contents = fs::read_to_string(config_file)?;
-    let data: Config = toml::from_str(&contents)?;
+    let mut data: Config = toml::from_str(&contents)?;
+
+    data.mail.relay.name = DEFAULT_RELAY.to_string();
+
+    validate(&data)?;
+
+    if let Some(smime) = data.mail.smime.as_mut() {
+        smime.keys = load_keys(smime)?;
+    }
+
+    for recipient in &mut data.mail.recipients {
+        if let Some(smime) = recipient.smime.as_mut() {
+            smime.keys = load_keys(smime)?;
+
+            let missing = missing_certificates(smime, &recipient.mails);
+
+            if !missing.is_empty() {
+                return Err(ServiceError::Conflict(format!(
+                    "Direction \"{}\" has no S/MIME certificate for: {}",
+                    recipient.direction,
+                    missing.join(", ")
+                )));
+            }
+        }
+
+        if let Some(pgp) = recipient.pgp.as_mut() {
+            pgp.key_mails = key_mails(pgp)?;
+
+            let missing = missing_keys(pgp, &recipient.mails);
+
+            if !missing.is_empty() {
+                return Err(ServiceError::Conflict(format!(
+                    "Direction \"{}\" has no PGP key for: {}",
+                    recipient.direction,
+                    missing.join(", ")
+                )));
+            }
+        }
+
+        for field in &mut recipient.fields {
+            if !field.pattern.is_empty() {
+                field.regex = Some(Regex::new(&field.pattern).map_err(|e| {
+                    ServiceError::Conflict(format!(
+                        "Invalid pattern from field \"{}\": {e}",
+                        field.name
+                    ))
+                })?);
+            }
+        }
+    }
+
+    data.spam_rules = compile_rules(&data)?;
+    data.template_env = compile_templates(&data)?;
+    data.dkim_signers = compile_signers(&data)?;
+
+    // without secret, tokens are only valid until the next restart
+    data.form_key = if data.form_secret.is_empty() {
+        rand::random::<[u8; 32]>().to_vec()
+    } else {
+        data.form_secret.as_bytes().to_vec()
+    };
 
     Ok(data)
 }
+
+/// Check that relay names are unique, that recipients only use existing relays,
+/// that CAPTCHAs and authentication have secrets, that custom fields don't use reserved names
+/// and that the default direction exists.
+fn validate(config: &Config) -> Result<(), ServiceError> {
+    let mut names = vec![];
+
+    for relay in config.all_relays() {
+        if relay.name.is_empty() || names.contains(&&relay.name) {
+            return Err(ServiceError::Conflict(format!(
+                "Relay name \"{}\" is empty or not unique",
+                relay.name
+            )));
+        }
+
+        names.push(&relay.name);
+
+        if !relay.sender().is_empty() && relay.sender().parse::<Address>().is_err() {
+            return Err(ServiceError::Conflict(format!(
+                "Relay \"{}\" needs a valid from address, the user \"{}\" is no mail address",
+                relay.name, relay.user
+            )));
+        }
+    }
+
+    if !config.mail.default_direction.is_empty()
+        && config.recipient(&config.mail.default_direction).is_none()
+    {
+        return Err(ServiceError::Conflict(format!(
+            "Default direction \"{}\" not exists",
+            config.mail.default_direction
+        )));
+    }
+
+    for recipient in &config.mail.recipients {
+        if recipient.mails.is_empty() {
+            return Err(ServiceError::Conflict(format!(
+                "Direction \"{}\" has no mail addresses",
+                recipient.direction
+            )));
+        }
+
+        if recipient
+            .captcha
+            .as_ref()
+            .is_some_and(|c| c.secret.is_empty())
+        {
+            return Err(ServiceError::Conflict(format!(
+                "Direction \"{}\" has a CAPTCHA without secret",
+                recipient.direction
+            )));
+        }
+
+        if recipient
+            .captcha
+            .as_ref()
+            .is_some_and(|c| !(0.0..=1.0).contains(&c.min_score))
+        {
+            return Err(ServiceError::Conflict(format!(
+                "Direction \"{}\" has a CAPTCHA min_score outside of 0.0 to 1.0",
+                recipient.direction
+            )));
+        }
+
+        if recipient.pgp.is_some() && recipient.smime.is_some() {
+            return Err(ServiceError::Conflict(format!(
+                "Direction \"{}\" can use PGP or S/MIME, but not both",
+                recipient.direction
+            )));
+        }
+
+        if recipient
+            .pgp
+            .as_ref()
+            .is_some_and(|p| p.keys.is_empty() && p.keyring.is_empty())
+        {
+            return Err(ServiceError::Conflict(format!(
+                "Direction \"{}\" has PGP encryption without keys",
+                recipient.direction
+            )));
+        }
+
+        if recipient
+            .auth
+            .as_ref()
+            .is_some_and(|a| a.keys.iter().all(|k| k.is_empty()))
+        {
+            return Err(ServiceError::Conflict(format!(
+                "Direction \"{}\" has authentication without keys",
+                recipient.direction
+            )));
+        }
+
+        for address in [
+            &recipient.confirmation.from,
+            &recipient.confirmation.reply_to,
+        ] {
+            if !address.is_empty() && address.parse::<Mailbox>().is_err() {
+                return Err(ServiceError::Conflict(format!(
+                    "Direction \"{}\" has invalid confirmation address \"{address}\"",
+                    recipient.direction
+                )));
+            }
+        }
+
+        for field in &recipient.fields {
+            if RESERVED_FIELDS.contains(&field.name.as_str()) {
+                return Err(ServiceError::Conflict(format!(
+                    "Direction \"{}\" uses reserved field name \"{}\"",
+                    recipient.direction, field.name
+                )));
+            }
+        }
+
+        let relays = recipient.relays();
+        let sender = config.sender(&relays);
+
+        for relay in &relays {
+            let Some(found) = config.relay(relay) else {
+                return Err(ServiceError::Conflict(format!(
+                    "Direction \"{}\" uses unknown relay \"{relay}\"",
+                    recipient.direction
+                )));
+            };
+
+            // the sender is in the signed message, a fallback relay can not change it anymore
+            if !found.sender().eq_ignore_ascii_case(sender) {
+                return Err(ServiceError::Conflict(format!(
+                    "Direction \"{}\" uses relays with different senders, \"{relay}\" sends from \"{}\" instead of \"{sender}\"",
+                    recipient.direction,
+                    found.sender()
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay(settings: &str) -> Relay {
+        toml::from_str(&format!(
+            "smtp = \"smtp.example.org\"\nport = 587\nuser = \"info@example.org\"\npassword = \"\"\n{settings}"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn relay_security() {
+        assert_eq!(relay("security = \"none\"").security(), Security::None);
+        assert_eq!(relay("").security(), Security::Starttls);
+
+        // legacy setting from older configs
+        assert_eq!(relay("starttls = true").security(), Security::Starttls);
+        assert_eq!(relay("starttls = false").security(), Security::Tls);
+        assert_eq!(
+            relay("starttls = false\nsecurity = \"starttls\"").security(),
+            Security::Starttls
+        );
+    }
+}
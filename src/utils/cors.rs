@@ -0,0 +1,245 @@
+use actix_web::{
+    dev::ServiceResponse,
+    http::header::{self, HeaderValue},
+    HttpRequest, HttpResponse,
+};
+
+use crate::{utils::config::Cors, CONFIG};
+
+fn origin(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::ORIGIN)
+        .and_then(|v| v.to_str().ok())
+}
+
+/// Check the Origin header from the request. Requests without Origin, or to directions
+/// without CORS settings, are allowed. Returns the reason, when the origin is not allowed.
+pub fn check_origin(req: &HttpRequest, cors: &Cors) -> Result<(), String> {
+    match origin(req) {
+        Some(origin) if cors.enabled() && !cors.allows_origin(origin) => {
+            Err(format!("origin {origin} not allowed"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Answer a preflight request, when origin, method and headers are allowed for the direction.
+pub fn preflight_response(req: &HttpRequest, cors: &Cors) -> Result<HttpResponse, String> {
+    let origin = origin(req).ok_or_else(|| "missing Origin header".to_string())?;
+
+    if !cors.enabled() || !cors.allows_origin(origin) {
+        return Err(format!("origin {origin} not allowed"));
+    }
+
+    let method = req
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    if !cors
+        .allowed_methods
+        .iter()
+        .any(|m| m.eq_ignore_ascii_case(method))
+    {
+        return Err(format!("method \"{method}\" not allowed"));
+    }
+
+    if let Some(headers) = req
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .and_then(|v| v.to_str().ok())
+    {
+        for name in headers.split(',').map(str::trim).filter(|h| !h.is_empty()) {
+            if !cors
+                .allowed_headers
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name))
+            {
+                return Err(format!("header \"{name}\" not allowed"));
+            }
+        }
+    }
+
+    Ok(HttpResponse::NoContent()
+        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin))
+        .insert_header((
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            cors.allowed_methods.join(", "),
+        ))
+        .insert_header((
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            cors.allowed_headers.join(", "),
+        ))
+        .insert_header((header::ACCESS_CONTROL_MAX_AGE, cors.max_age_seconds))
+        .insert_header((header::VARY, "Origin"))
+        .finish())
+}
+
+/// Add the CORS headers to responses for allowed origins, also to error responses,
+/// so the browser can read them.
+pub fn add_headers<B>(res: &mut ServiceResponse<B>) {
+    let direction = res
+        .request()
+        .match_info()
+        .get("direction")
+        .unwrap_or_default();
+
+    allow_origin(res, CONFIG.cors(direction));
+}
+
+fn allow_origin<B>(res: &mut ServiceResponse<B>, cors: &Cors) {
+    let req = res.request();
+
+    let Some(value) = origin(req)
+        .filter(|o| cors.enabled() && cors.allows_origin(o))
+        .and_then(|o| HeaderValue::from_str(o).ok())
+    else {
+        return;
+    };
+
+    let headers = res.headers_mut();
+
+    if !headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{http::StatusCode, test::TestRequest};
+
+    fn cors() -> Cors {
+        Cors {
+            allowed_origins: vec!["https://example.org".to_string()],
+            allowed_methods: vec!["POST".to_string()],
+            allowed_headers: vec!["Content-Type".to_string(), "X-Token".to_string()],
+            max_age_seconds: 600,
+        }
+    }
+
+    fn header_value<B>(res: &ServiceResponse<B>, name: header::HeaderName) -> Option<&str> {
+        res.headers().get(name).and_then(|v| v.to_str().ok())
+    }
+
+    #[test]
+    fn allowed_origin() {
+        let req = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://example.org"))
+            .to_http_request();
+
+        assert!(check_origin(&req, &cors()).is_ok());
+
+        let mut res = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://example.org"))
+            .to_srv_response(HttpResponse::Ok().finish());
+        allow_origin(&mut res, &cors());
+
+        assert_eq!(
+            header_value(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://example.org")
+        );
+        assert_eq!(header_value(&res, header::VARY), Some("Origin"));
+    }
+
+    #[test]
+    fn disallowed_origin() {
+        let req = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://evil.example"))
+            .to_http_request();
+
+        assert_eq!(
+            check_origin(&req, &cors()),
+            Err("origin https://evil.example not allowed".to_string())
+        );
+        assert!(preflight_response(&req, &cors()).is_err());
+
+        let mut res = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://evil.example"))
+            .to_srv_response(HttpResponse::Ok().finish());
+        allow_origin(&mut res, &cors());
+
+        assert!(header_value(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn missing_origin() {
+        let req = TestRequest::default().to_http_request();
+
+        // same origin requests and other clients, like curl, send no Origin
+        assert!(check_origin(&req, &cors()).is_ok());
+        assert_eq!(
+            preflight_response(&req, &cors()).unwrap_err(),
+            "missing Origin header"
+        );
+
+        let mut res = TestRequest::default().to_srv_response(HttpResponse::Ok().finish());
+        allow_origin(&mut res, &cors());
+
+        assert!(header_value(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn disabled_cors() {
+        let req = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://example.org"))
+            .to_http_request();
+
+        assert!(check_origin(&req, &Cors::default()).is_ok());
+        assert!(preflight_response(&req, &Cors::default()).is_err());
+    }
+
+    #[test]
+    fn preflight_headers() {
+        let req = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://example.org"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+            .insert_header((
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "content-type, x-token",
+            ))
+            .to_http_request();
+        let res = preflight_response(&req, &cors()).unwrap();
+        let value = |name| res.headers().get(name).and_then(|v| v.to_str().ok());
+
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            value(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://example.org")
+        );
+        assert_eq!(value(header::ACCESS_CONTROL_ALLOW_METHODS), Some("POST"));
+        assert_eq!(
+            value(header::ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("Content-Type, X-Token")
+        );
+        assert_eq!(value(header::ACCESS_CONTROL_MAX_AGE), Some("600"));
+        assert_eq!(value(header::VARY), Some("Origin"));
+    }
+
+    #[test]
+    fn preflight_not_allowed() {
+        let method = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://example.org"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "DELETE"))
+            .to_http_request();
+
+        assert_eq!(
+            preflight_response(&method, &cors()).unwrap_err(),
+            "method \"DELETE\" not allowed"
+        );
+
+        let headers = TestRequest::default()
+            .insert_header((header::ORIGIN, "https://example.org"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, "Authorization"))
+            .to_http_request();
+
+        assert_eq!(
+            preflight_response(&headers, &cors()).unwrap_err(),
+            "header \"Authorization\" not allowed"
+        );
+    }
+}
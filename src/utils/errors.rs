@@ -16,12 +16,24 @@ pub enum ServiceError {
     #[display(fmt = "Conflict: {_0}")]
     Conflict(String),
 
+    #[display(fmt = "Forbidden: {_0}")]
+    Forbidden(String),
+
+    #[display(fmt = "NotFound: {_0}")]
+    NotFound(String),
+
     #[display(fmt = "NoContent: {_0}")]
     NoContent(String),
 
+    #[display(fmt = "PayloadTooLarge: {_0}")]
+    PayloadTooLarge(String),
+
     #[display(fmt = "ServiceUnavailable: {_0}")]
     ServiceUnavailable(String),
 
+    #[display(fmt = "Unauthorized: {_0}")]
+    Unauthorized(String),
+
     #[display(fmt = "UnprocessableEntity: {_0}")]
     UnprocessableEntity(String),
 }
@@ -34,10 +46,16 @@ impl ResponseError for ServiceError {
             }
             ServiceError::BadRequest(ref message) => HttpResponse::BadRequest().json(message),
             ServiceError::Conflict(ref message) => HttpResponse::Conflict().json(message),
+            ServiceError::Forbidden(ref message) => HttpResponse::Forbidden().json(message),
+            ServiceError::NotFound(ref message) => HttpResponse::NotFound().json(message),
             ServiceError::NoContent(ref message) => HttpResponse::NoContent().json(message),
+            ServiceError::PayloadTooLarge(ref message) => {
+                HttpResponse::PayloadTooLarge().json(message)
+            }
             ServiceError::ServiceUnavailable(ref message) => {
                 HttpResponse::ServiceUnavailable().json(message)
             }
+            ServiceError::Unauthorized(ref message) => HttpResponse::Unauthorized().json(message),
             ServiceError::UnprocessableEntity(ref message) => {
                 HttpResponse::UnprocessableEntity().json(message)
             }
@@ -49,7 +67,7 @@ impl From<ServiceError> for io::Error {
     fn from(err: ServiceError) -> Self {
         error!("{err:?}");
 
-        io::Error::new(io::ErrorKind::Other, format!("{err:?}"))
+        io::Error::other(format!("{err:?}"))
     }
 }
 
@@ -117,6 +135,14 @@ impl From<std::net::AddrParseError> for ServiceError {
     }
 }
 
+impl From<actix_web::error::PayloadError> for ServiceError {
+    fn from(err: actix_web::error::PayloadError) -> ServiceError {
+        error!("{err:?}");
+
+        ServiceError::BadRequest(err.to_string())
+    }
+}
+
 impl From<actix_multipart::MultipartError> for ServiceError {
     fn from(err: actix_multipart::MultipartError) -> ServiceError {
         error!("{err:?}");
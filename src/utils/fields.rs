@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+use lazy_static::lazy_static;
+use lettre::Address;
+use regex::Regex;
+use voca_rs::Voca;
+
+use crate::utils::config::{FieldFormat, FormField, FIELD_MAX_LENGTH};
+
+lazy_static! {
+    static ref PHONE: Regex = Regex::new(r"^\+?[0-9 ()./-]{3,30}$").unwrap();
+}
+
+fn valid_phone(value: &str) -> bool {
+    PHONE.is_match(value) && value.chars().filter(|c| c.is_ascii_digit()).count() >= 3
+}
+
+fn is_listed(name: &str, config: &[FormField]) -> bool {
+    config.iter().any(|f| f.name == name)
+}
+
+/// First custom field, which is not in the field list from the direction.
+pub fn unknown<'a>(fields: &'a BTreeMap<String, String>, config: &[FormField]) -> Option<&'a str> {
+    fields
+        .keys()
+        .find(|k| !is_listed(k, config))
+        .map(String::as_str)
+}
+
+/// Check custom fields against the field list from the direction.
+///
+/// Listed fields are checked with their settings, other fields only against the default maximum length.
+/// Returns the reason, when a field is missing or not valid.
+pub fn validate(fields: &BTreeMap<String, String>, config: &[FormField]) -> Result<(), String> {
+    for (name, value) in fields {
+        if !is_listed(name, config) && value.trim().chars().count() > FIELD_MAX_LENGTH {
+            return Err(format!("Field too long: {name}"));
+        }
+    }
+
+    for field in config {
+        let value = fields
+            .get(&field.name)
+            .map(|v| v.trim())
+            .unwrap_or_default();
+
+        if value.is_empty() {
+            if field.required {
+                return Err(format!("Missing field: {}", field.name));
+            }
+
+            continue;
+        }
+
+        if value.chars().count() > field.max_length {
+            return Err(format!("Field too long: {}", field.name));
+        }
+
+        let valid = match field.format {
+            FieldFormat::Text => true,
+            FieldFormat::Email => value.parse::<Address>().is_ok(),
+            FieldFormat::Phone => valid_phone(value),
+        };
+
+        if !valid || field.regex.as_ref().is_some_and(|r| !r.is_match(value)) {
+            return Err(format!("Invalid field: {}", field.name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Custom fields as rows with name, label and value.
+/// Listed fields are ordered like in the config, other fields follow by name.
+pub fn rows<'a>(
+    fields: &'a BTreeMap<String, String>,
+    config: &'a [FormField],
+) -> Vec<(&'a str, &'a str, &'a str)> {
+    let mut rows = vec![];
+
+    for field in config {
+        if let Some(value) = fields.get(&field.name).filter(|v| !v.trim().is_empty()) {
+            rows.push((field.name.as_str(), field.label(), value.trim()));
+        }
+    }
+
+    for (name, value) in fields {
+        if !is_listed(name, config) && !value.trim().is_empty() {
+            rows.push((name.as_str(), name.as_str(), value.trim()));
+        }
+    }
+
+    rows
+}
+
+/// Render custom fields as labelled list for text mails, or as table for html mails.
+pub fn render(fields: &BTreeMap<String, String>, config: &[FormField], html: bool) -> String {
+    let rows = rows(fields, config);
+
+    if rows.is_empty() {
+        return String::new();
+    }
+
+    if html {
+        let mut table = String::from("<table>\n");
+
+        for (_, label, value) in rows {
+            table.push_str(&format!(
+                "<tr><th align=\"left\">{}</th><td>{}</td></tr>\n",
+                label._escape_html(),
+                value._escape_html().replace('\n', "<br>")
+            ));
+        }
+
+        table.push_str("</table>");
+
+        table
+    } else {
+        rows.iter()
+            .map(|(_, label, value)| format!("{label}: {value}"))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
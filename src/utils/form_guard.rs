@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::utils::{config::Recipients, mailer::Msg};
+use crate::CONFIG;
+
+type HmacSha256 = Hmac<Sha256>;
+
+lazy_static! {
+    /// Nonces from tokens which are already used, with the time until the tokens are valid
+    static ref USED_NONCES: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Sign timestamp and nonce together with the direction, so a token can not be used for other directions.
+fn signature(key: &[u8], direction: &str, timestamp: u64, nonce: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(format!("{direction}:{timestamp}:{nonce}").as_bytes());
+
+    mac
+}
+
+fn new_token(key: &[u8], direction: &str, timestamp: u64) -> String {
+    let nonce = hex::encode(rand::random::<[u8; 16]>());
+    let signature = hex::encode(
+        signature(key, direction, timestamp, &nonce)
+            .finalize()
+            .into_bytes(),
+    );
+
+    format!("{timestamp}.{nonce}.{signature}")
+}
+
+/// Create a form token for the direction, the token has the format **{timestamp}.{nonce}.{signature}**.
+pub fn create_token(direction: &str) -> String {
+    new_token(&CONFIG.form_key, direction, now())
+}
+
+/// Check that the token is signed from us, that the form was filled in the allowed time frame
+/// and that the token was not used before.
+fn check_token(key: &[u8], token: &str, recipient: &Recipients) -> Result<(), String> {
+    let mut parts = token.splitn(3, '.');
+    let (Some(timestamp), Some(nonce), Some(sig)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err("missing or malformed token".to_string());
+    };
+    let timestamp: u64 = timestamp
+        .parse()
+        .map_err(|_| "malformed token timestamp".to_string())?;
+    let sig = hex::decode(sig).map_err(|_| "malformed token signature".to_string())?;
+
+    signature(key, &recipient.direction, timestamp, nonce)
+        .verify_slice(&sig)
+        .map_err(|_| "invalid token signature".to_string())?;
+
+    let current = now();
+    let age = current
+        .checked_sub(timestamp)
+        .ok_or_else(|| "token from the future".to_string())?;
+
+    if age < recipient.min_fill_seconds {
+        return Err(format!("form filled in {age}s"));
+    }
+
+    if age > recipient.max_fill_minutes * 60 {
+        return Err(format!("token expired, {age}s old"));
+    }
+
+    let mut used = USED_NONCES.lock().unwrap();
+
+    // expired tokens are rejected anyway, no need to remember them
+    used.retain(|_, expires| *expires >= current);
+
+    if used
+        .insert(
+            format!("{}:{nonce}", recipient.direction),
+            timestamp + recipient.max_fill_minutes * 60,
+        )
+        .is_some()
+    {
+        return Err("token already used".to_string());
+    }
+
+    Ok(())
+}
+
+/// Check the honeypot field and the form token from the direction.
+/// Returns the reason, when the message looks like it comes from a bot.
+pub fn check_form(msg: &Msg, recipient: &Recipients) -> Result<(), String> {
+    if !recipient.honeypot.is_empty() {
+        match msg.extra.get(&recipient.honeypot) {
+            None | Some(Value::Null) => {}
+            Some(Value::String(s)) if s.is_empty() => {}
+            Some(_) => return Err(format!("honeypot field \"{}\" filled", recipient.honeypot)),
+        }
+    }
+
+    if recipient.form_token {
+        check_token(&CONFIG.form_key, &msg.token, recipient)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"test-key";
+
+    fn recipient(direction: &str) -> Recipients {
+        toml::from_str(&format!(
+            r#"
+            allow_html = false
+            direction = "{direction}"
+            mails = ["staff@example.org"]
+            send_copy = false
+            form_token = true
+            min_fill_seconds = 3
+            max_fill_minutes = 1
+            "#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn valid_token_only_once() {
+        let recipient = recipient("once");
+        let token = new_token(KEY, "once", now() - 5);
+
+        assert_eq!(check_token(KEY, &token, &recipient), Ok(()));
+        assert_eq!(
+            check_token(KEY, &token, &recipient),
+            Err("token already used".to_string())
+        );
+    }
+
+    #[test]
+    fn new_tokens_are_unique() {
+        let timestamp = now() - 5;
+
+        assert_ne!(
+            new_token(KEY, "unique", timestamp),
+            new_token(KEY, "unique", timestamp)
+        );
+    }
+
+    #[test]
+    fn token_from_other_direction_or_key() {
+        let recipient = recipient("contact");
+        let other_direction = new_token(KEY, "support", now() - 5);
+        let other_key = new_token(b"other-key", "contact", now() - 5);
+
+        assert!(check_token(KEY, &other_direction, &recipient).is_err());
+        assert!(check_token(KEY, &other_key, &recipient).is_err());
+    }
+
+    #[test]
+    fn token_age() {
+        let recipient = recipient("age");
+
+        assert!(check_token(KEY, &new_token(KEY, "age", now()), &recipient)
+            .unwrap_err()
+            .starts_with("form filled in"));
+        assert!(
+            check_token(KEY, &new_token(KEY, "age", now() - 61), &recipient)
+                .unwrap_err()
+                .starts_with("token expired")
+        );
+        assert_eq!(
+            check_token(KEY, &new_token(KEY, "age", now() + 60), &recipient),
+            Err("token from the future".to_string())
+        );
+    }
+
+    #[test]
+    fn malformed_tokens() {
+        let recipient = recipient("malformed");
+
+        for token in ["", "123", "abc.def.012", "123.nonce.xyz"] {
+            assert!(check_token(KEY, token, &recipient).is_err());
+        }
+    }
+
+    #[test]
+    fn honeypot_must_be_empty() {
+        let mut recipient = recipient("honeypot");
+        recipient.honeypot = "website".to_string();
+        recipient.form_token = false;
+        let mut msg = Msg::default();
+
+        assert!(check_form(&msg, &recipient).is_ok());
+
+        msg.extra.insert("website".to_string(), "".into());
+        assert!(check_form(&msg, &recipient).is_ok());
+
+        msg.extra
+            .insert("website".to_string(), "https://spam.example".into());
+        assert!(check_form(&msg, &recipient).is_err());
+    }
+}
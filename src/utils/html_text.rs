@@ -0,0 +1,211 @@
+use html_parser::{Dom, Element, Node};
+use voca_rs::Voca;
+
+/// Elements which are not visible in the mail
+const SKIP_ELEMENTS: [&str; 5] = ["head", "script", "style", "template", "title"];
+
+/// Elements which stand in their own paragraph
+const BLOCK_ELEMENTS: [&str; 17] = [
+    "address",
+    "article",
+    "blockquote",
+    "div",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "ol",
+    "p",
+    "section",
+    "table",
+    "ul",
+];
+
+/// Add line breaks to the end of the text, until it ends with at least **count** line breaks.
+fn break_lines(text: &mut String, count: usize) {
+    let text_end = text.trim_end_matches(' ').len();
+    text.truncate(text_end);
+
+    let breaks = text.len() - text.trim_end_matches('\n').len();
+
+    if !text.is_empty() && breaks < count {
+        text.push_str(&"\n".repeat(count - breaks));
+    }
+}
+
+fn push_words(text: &mut String, input: &str) {
+    let input = input._unescape_html();
+    let words: Vec<&str> = input.split_whitespace().collect();
+
+    if words.is_empty() {
+        if !input.is_empty() && !text.ends_with([' ', '\n']) && !text.is_empty() {
+            text.push(' ');
+        }
+
+        return;
+    }
+
+    if input.starts_with(char::is_whitespace) && !text.ends_with([' ', '\n']) && !text.is_empty() {
+        text.push(' ');
+    }
+
+    text.push_str(&words.join(" "));
+
+    if input.ends_with(char::is_whitespace) {
+        text.push(' ');
+    }
+}
+
+fn attribute<'a>(element: &'a Element, name: &str) -> Option<&'a str> {
+    element
+        .attributes
+        .get(name)
+        .and_then(|v| v.as_deref())
+        .filter(|v| !v.is_empty())
+}
+
+fn push_nodes(text: &mut String, nodes: &[Node]) {
+    for node in nodes {
+        match node {
+            Node::Text(t) => push_words(text, t),
+            Node::Element(element) => push_element(text, element),
+            Node::Comment(_) => {}
+        }
+    }
+}
+
+fn push_element(text: &mut String, element: &Element) {
+    let name = element.name.to_lowercase();
+
+    if SKIP_ELEMENTS.contains(&name.as_str()) {
+        return;
+    }
+
+    match name.as_str() {
+        "br" => {
+            let text_end = text.trim_end_matches(' ').len();
+            text.truncate(text_end);
+            text.push('\n');
+        }
+        "hr" => {
+            break_lines(text, 2);
+            text.push_str("----------");
+            break_lines(text, 2);
+        }
+        "img" => {
+            if let Some(alt) = attribute(element, "alt") {
+                push_words(text, alt);
+            }
+        }
+        "li" => {
+            break_lines(text, 1);
+            text.push_str("- ");
+            push_nodes(text, &element.children);
+            break_lines(text, 1);
+        }
+        "tr" => {
+            break_lines(text, 1);
+            push_nodes(text, &element.children);
+            break_lines(text, 1);
+        }
+        "th" => {
+            push_nodes(text, &element.children);
+            text.push_str(": ");
+        }
+        "td" => {
+            push_nodes(text, &element.children);
+            text.push(' ');
+        }
+        "a" => {
+            let start = text.len();
+            push_nodes(text, &element.children);
+
+            if let Some(href) = attribute(element, "href") {
+                if text[start..].trim() != href.trim_start_matches("mailto:") {
+                    text.push_str(&format!(" ({href})"));
+                }
+            }
+        }
+        _ if BLOCK_ELEMENTS.contains(&name.as_str()) => {
+            break_lines(text, 2);
+            push_nodes(text, &element.children);
+            break_lines(text, 2);
+        }
+        _ => push_nodes(text, &element.children),
+    }
+}
+
+/// Plain text version from a parsed html body, for the text alternative in the mail.
+/// Paragraphs are separated by empty lines, links keep their target and table rows become lines.
+pub fn html_to_text(dom: &Dom) -> String {
+    let mut text = String::new();
+    push_nodes(&mut text, &dom.children);
+
+    text.lines()
+        .map(|l| l.trim())
+        .collect::<Vec<&str>>()
+        .join("\n")
+        .split("\n\n")
+        .map(|p| p.trim_matches('\n'))
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(html: &str) -> String {
+        html_to_text(&Dom::parse(html).unwrap())
+    }
+
+    #[test]
+    fn paragraphs_and_line_breaks() {
+        assert_eq!(
+            text("<h1>Hello</h1><p>first  line<br>second line</p><div>next</div>"),
+            "Hello\n\nfirst line\nsecond line\n\nnext"
+        );
+    }
+
+    #[test]
+    fn links_keep_their_target() {
+        assert_eq!(
+            text(r#"<p>Read <a href="https://example.org/faq">the FAQ</a>.</p>"#),
+            "Read the FAQ (https://example.org/faq)."
+        );
+        assert_eq!(
+            text(r#"<a href="mailto:info@example.org">info@example.org</a>"#),
+            "info@example.org"
+        );
+    }
+
+    #[test]
+    fn lists_and_tables() {
+        assert_eq!(text("<ul><li>one</li><li>two</li></ul>"), "- one\n- two");
+        assert_eq!(
+            text("<table><tr><th>Phone</th><td>123</td></tr><tr><th>Company</th><td>ACME</td></tr></table>"),
+            "Phone: 123\nCompany: ACME"
+        );
+    }
+
+    #[test]
+    fn hidden_elements_are_skipped() {
+        assert_eq!(
+            text("<html><head><title>T</title><style>p {}</style></head><body><p>Visible</p><script>x()</script></body></html>"),
+            "Visible"
+        );
+    }
+
+    #[test]
+    fn entities_and_images() {
+        assert_eq!(
+            text(r#"<p>Fish &amp; Chips <img src="x.png" alt="logo"></p><hr><p>end</p>"#),
+            "Fish & Chips logo\n\n----------\n\nend"
+        );
+    }
+}
@@ -8,7 +8,8 @@ use actix_governor::{
     KeyExtractor, SimpleKeyExtractionError,
 };
 use actix_web::{
-    dev::ServiceRequest, http::header::ContentType, web, HttpResponse, HttpResponseBuilder,
+    dev::ServiceRequest, http::header::ContentType, web, HttpRequest, HttpResponse,
+    HttpResponseBuilder,
 };
 use log::{error, trace};
 
@@ -16,38 +17,18 @@ use log::{error, trace};
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct IpExtractor;
 
-/// The **KeyExtractor** trait has two associated types: **Key** and **KeyExtractionError**. For **IpExtractor**,
-/// **Key** is **IpAddr**, which means the key is an IP address. **KeyExtractionError** is
-/// **SimpleKeyExtractionError<&'static str>**, which means the error type is a simple error with a
-/// static string message.
-///
-/// The **name** method returns a static string that is the name of the key extractor. This is only
-/// compiled when the "log" feature is enabled.
-///
-/// The **extract** method is where the IP address is extracted from the request. It first gets the reverse
+/// The **real_ip** function extracts the client IP address from the request. It first gets the reverse
 /// proxy IP from the app data. If the app data doesn't contain an IP address, it defaults to "0.0.0.0".
 /// It then gets the peer IP from the request, which is the IP address of the client that made the request.
 ///
-/// The method then checks if the peer IP is the same as the reverse proxy IP. If it is, it means the
+/// The function then checks if the peer IP is the same as the reverse proxy IP. If it is, it means the
 /// request is coming from the reverse proxy, so it tries to get the real IP from the **Forwarded** or
-/// **X-Forwarded-For** headers. If it can't get the real IP, it logs an error and returns a
-/// **SimpleKeyExtractionError**.
+/// **X-Forwarded-For** headers. If it can't get the real IP, it logs an error and returns an error message.
 ///
 /// If the peer IP is not the same as the reverse proxy IP, it means the request is not coming from the reverse
-/// proxy, so it uses the peer IP as the key. If it can't get the peer IP, it logs an error and returns a
-/// **SimpleKeyExtractionError**.
-
-impl KeyExtractor for IpExtractor {
-    type Key = IpAddr;
-
-    type KeyExtractionError = SimpleKeyExtractionError<&'static str>;
-
-    #[cfg(feature = "log")]
-    fn name(&self) -> &'static str {
-        "real IP"
-    }
-
-    fn extract(&self, req: &ServiceRequest) -> Result<Self::Key, Self::KeyExtractionError> {
+/// proxy, so it uses the peer IP. If it can't get the peer IP, it logs an error and returns an error message.
+impl IpExtractor {
+    pub fn real_ip(req: &HttpRequest) -> Result<IpAddr, &'static str> {
         // Get the reverse proxy IP that we put in app data
         let reverse_proxy_ip = req
             .app_data::<web::Data<IpAddr>>()
@@ -65,24 +46,20 @@ impl KeyExtractor for IpExtractor {
                 .realip_remote_addr()
                 .ok_or_else(|| {
                     error!("Could not extract real IP address from request");
-                    SimpleKeyExtractionError::new("Could not extract real IP address from request")
+                    "Could not extract real IP address from request"
                 })
                 .and_then(|str| {
                     SocketAddr::from_str(str)
                         .map(|socket| socket.ip())
                         .or_else(|_| IpAddr::from_str(str))
-                        .map_err(|_| {
-                            SimpleKeyExtractionError::new(
-                                "Could not extract real IP address from request",
-                            )
-                        })
+                        .map_err(|_| "Could not extract real IP address from request")
                 }),
             // The request is not coming from the reverse proxy, we use peer IP
             _ => connection_info
                 .peer_addr()
                 .ok_or_else(|| {
                     error!("Could not extract peer IP address from request");
-                    SimpleKeyExtractionError::new("Could not extract peer IP address from request")
+                    "Could not extract peer IP address from request"
                 })
                 .and_then(|str| {
                     SocketAddr::from_str(str)
@@ -90,13 +67,35 @@ impl KeyExtractor for IpExtractor {
                         .or_else(|_| IpAddr::from_str(str))
                         .map_err(|e| {
                             error!("{e}: {str}");
-                            SimpleKeyExtractionError::new(
-                                "Could not extract peer IP address from request",
-                            )
+                            "Could not extract peer IP address from request"
                         })
                 }),
         }
     }
+}
+
+/// The **KeyExtractor** trait has two associated types: **Key** and **KeyExtractionError**. For **IpExtractor**,
+/// **Key** is **IpAddr**, which means the key is an IP address. **KeyExtractionError** is
+/// **SimpleKeyExtractionError<&'static str>**, which means the error type is a simple error with a
+/// static string message.
+///
+/// The **name** method returns a static string that is the name of the key extractor. This is only
+/// compiled when the "log" feature is enabled.
+///
+/// The **extract** method uses **real_ip** to get the IP address from the request, which is the key.
+impl KeyExtractor for IpExtractor {
+    type Key = IpAddr;
+
+    type KeyExtractionError = SimpleKeyExtractionError<&'static str>;
+
+    #[cfg(feature = "log")]
+    fn name(&self) -> &'static str {
+        "real IP"
+    }
+
+    fn extract(&self, req: &ServiceRequest) -> Result<Self::Key, Self::KeyExtractionError> {
+        IpExtractor::real_ip(req.request()).map_err(SimpleKeyExtractionError::new)
+    }
 
     // This function is only needed because we are removing the seconds to wait.
     // If the original message is needed, remove the hole function.
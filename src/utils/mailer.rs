@@ -1,39 +1,53 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     ffi::OsStr,
     fs,
-    io::{self, BufRead},
+    io::{self, Read},
     path::Path,
 };
 
 use html_parser::Dom;
 use lettre::{
     message::{
-        header::{self, ContentType},
-        Attachment, MultiPart, SinglePart,
+        header::{ContentType, HeaderName, HeaderValue},
+        Attachment, Mailbox, MultiPart, SinglePart,
     },
-    transport::smtp::authentication::Credentials,
-    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    Message,
 };
-use log::{error, trace};
-use regex::Regex;
+use log::{error, info, trace, warn};
+use minijinja::Value;
 use serde::{Deserialize, Serialize};
 use voca_rs::Voca;
 
-use crate::utils::errors::ServiceError;
+use crate::utils::{
+    attachments::mime_type,
+    config::{Confirmation, FormField, DEFAULT_RELAY},
+    dkim::sign,
+    errors::ServiceError,
+    fields::render,
+    html_text::html_to_text,
+    pgp::encrypted_part,
+    queue::{enqueue, enqueue_raw, quarantine},
+    sendmail::{mime_message, missing_recipient, parse_message, MailHeaders, HEADER_RECIPIENT},
+    smime::protect,
+    templates::{self, HTML, SUBJECT, TEXT},
+};
 use crate::{ARGS, CONFIG};
 
-/// Ignore lines from stdin, that starts with this strings
-const IGNORE_LINES: [&str; 9] = [
-    "From:",
-    "To:",
-    "Subject:",
-    "MIME-Version:",
-    "Content-Type:",
-    "Content-Transfer-Encoding:",
-    "X-Cron-Env:",
-    "X-Cron-Env:",
-    "X-Cron-Env:",
-];
+/// Body from a mail, a single part or a multipart with attachments
+pub enum MimeBody {
+    Single(SinglePart),
+    Multi(MultiPart),
+}
+
+impl MimeBody {
+    pub fn formatted(&self) -> Vec<u8> {
+        match self {
+            Self::Single(part) => part.formatted(),
+            Self::Multi(part) => part.formatted(),
+        }
+    }
+}
 
 /// Mail struct
 ///
@@ -46,6 +60,12 @@ const IGNORE_LINES: [&str; 9] = [
 /// * **mail** - A string that contains the mail address
 /// * **subject** - A string that contains the mail subject
 /// * **text** - A string that contains the mail text
+/// * **quarantine** - A bool that marks spam suspects, which are kept in quarantine instead of sent
+/// * **token** - A string that contains the signed form token
+/// * **fields** - Custom form fields, like name or phone, which are rendered into the mail
+/// * **extra** - All other fields, like the honeypot field
+/// * **meta** - Metadata from the request, for the templates
+/// * **headers** - Headers from a message on stdin, in sendmail mode
 ///
 /// The struct has the following methods:
 /// * **new** - The constructor for the struct
@@ -63,6 +83,30 @@ pub struct Msg {
     pub text: String,
     #[serde(skip_deserializing)]
     pub send_copy: bool,
+    #[serde(skip)]
+    pub quarantine: bool,
+    #[serde(default)]
+    pub token: String,
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+    #[serde(skip)]
+    pub meta: RequestMeta,
+    #[serde(skip)]
+    pub headers: MailHeaders,
+}
+
+/// Metadata from the HTTP request, which is available in the templates
+///
+/// * **ip** - The real client IP
+/// * **user_agent** - The User-Agent header
+/// * **received** - Time when the request was received
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RequestMeta {
+    pub ip: String,
+    pub user_agent: String,
+    pub received: String,
 }
 
 /// The `Msg` struct has an associated `new` function, which is a constructor that takes values for
@@ -72,9 +116,6 @@ pub struct Msg {
 /// `text` field as a `Dom` object (presumably representing a Document Object Model). If the parsing
 /// is successful and the `Dom` object has exactly one child that contains text, it returns `TEXT_PLAIN`
 /// as the content type. Otherwise, it returns `TEXT_HTML`. If the parsing fails, it also returns `TEXT_PLAIN`.
-///
-/// The `is_spam` method returns true if the `text` or `subject` fields contain any of the words in the
-/// `block_words` field of the `CONFIG` object.
 impl Msg {
     pub fn new(
         direction: Option<String>,
@@ -92,35 +133,24 @@ impl Msg {
             subject,
             text,
             send_copy: false,
+            quarantine: false,
+            token: String::new(),
+            fields: BTreeMap::new(),
+            extra: HashMap::new(),
+            meta: RequestMeta::default(),
+            headers: MailHeaders::default(),
         }
     }
 
-    pub fn content_type(&self) -> header::ContentType {
-        match Dom::parse(&self.text) {
-            Ok(dom) => {
-                if (dom.children.len() == 1 && dom.children[0].text().is_some()) || !self.allow_html
-                {
-                    return header::ContentType::TEXT_PLAIN;
-                }
-
-                header::ContentType::TEXT_HTML
-            }
-            Err(_) => header::ContentType::TEXT_PLAIN,
-        }
-    }
-
-    pub fn is_spam(&self) -> bool {
-        let mut spam = false;
-
-        for word in &CONFIG.mail.block_words {
-            let re = Regex::new(&format!(r"\b{}\b", word)).unwrap();
-            if re.is_match(&self.subject) || re.is_match(&self.text) {
-                spam = true;
-                break;
-            }
+    /// Parsed html from the text, **None** when html is not allowed or when the text is plain text.
+    pub fn html_dom(&self) -> Option<Dom> {
+        if !self.allow_html {
+            return None;
         }
 
-        spam
+        Dom::parse(&self.text)
+            .ok()
+            .filter(|dom| !(dom.children.len() == 1 && dom.children[0].text().is_some()))
     }
 }
 
@@ -136,59 +166,50 @@ impl Default for Msg {
             subject: "My Subject".to_string(),
             text: "My Text".to_string(),
             send_copy: false,
+            quarantine: false,
+            token: String::new(),
+            fields: BTreeMap::new(),
+            extra: HashMap::new(),
+            meta: RequestMeta::default(),
+            headers: MailHeaders::default(),
         }
     }
 }
 
-async fn send(message: Message) -> Result<(), ServiceError> {
-    let credentials = Credentials::new(CONFIG.mail.user.clone(), CONFIG.mail.password.clone());
-
-    // create transporter based on starttls configuration
-    let transporter = if CONFIG.mail.starttls {
-        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&CONFIG.mail.smtp)
-    } else {
-        AsyncSmtpTransport::<Tokio1Executor>::relay(&CONFIG.mail.smtp)
-    };
-
-    let mailer = transporter?.credentials(credentials).build();
-
-    trace!("Mail: {message:?}");
-
-    mailer.send(message.clone()).await?;
-
-    // backup mail to file if mail_archive is set
-    if !CONFIG.mail_archive.is_empty() && Path::new(&CONFIG.mail_archive).is_dir() {
-        let backup = AsyncFileTransport::<Tokio1Executor>::new(Path::new(&CONFIG.mail_archive));
+/// Take Msg object and put it into the delivery queue, or into quarantine for spam suspects.
+/// Returns the queue id from the message to the recipients.
+pub async fn message_worker(mut msg: Msg) -> Result<String, ServiceError> {
+    let mut message = Message::builder();
+    let mut recipients = vec![];
+    let mut relays = vec![DEFAULT_RELAY.to_string()];
+    let mut field_config: &[FormField] = &[];
+    let mut confirmation = None;
+    let mut pgp = None;
+    let mut smime = CONFIG.mail.smime.as_ref();
 
-        backup.send(message).await?;
-    }
+    // directions are used to send mails to different recipients and comes from API routes
+    if msg.direction.is_none() {
+        let headers = &msg.headers;
+        recipients = msg.mail.split(',').map(|r| r.trim().to_string()).collect();
 
-    Ok(())
-}
+        for cc in &headers.cc {
+            message = message.cc(cc.clone());
+        }
 
-/// Take Msg object and send it to the mail server
-pub async fn message_worker(mut msg: Msg) -> Result<(), ServiceError> {
-    let mut message = Message::builder().subject(&msg.subject);
-    let mut recipients = vec![];
+        for bcc in &headers.bcc {
+            message = message.bcc(bcc.clone());
+        }
 
-    // full_name comes mostly from system mails and it is implemented to be compatible with sendmail
-    match &ARGS.full_name {
-        Some(full_name) => {
-            message = message.from(format!("{full_name} <{}>", CONFIG.mail.user).parse()?)
+        for reply_to in &headers.reply_to {
+            message = message.reply_to(reply_to.clone());
         }
-        None => message = message.from(CONFIG.mail.user.parse()?),
-    };
 
-    // directions are used to send mails to different recipients and comes from API routes
-    if msg.direction.is_none() {
-        if msg.mail.contains(',') {
-            for res in msg.mail.split(',') {
-                let r = res.trim();
+        if let Some(date) = headers.date {
+            message = message.date(date);
+        }
 
-                message = message.to(r.parse()?);
-            }
-        } else {
-            message = message.to(msg.mail.parse()?);
+        if headers.message_id.is_some() {
+            message = message.message_id(headers.message_id.clone());
         }
     } else {
         message = message.reply_to(msg.mail.parse()?);
@@ -198,68 +219,239 @@ pub async fn message_worker(mut msg: Msg) -> Result<(), ServiceError> {
                 msg.allow_html = recipient.allow_html;
                 msg.send_copy = recipient.send_copy;
                 recipients = recipient.mails.clone();
+                relays = recipient.relays();
+                field_config = &recipient.fields;
+                confirmation = Some(&recipient.confirmation);
+                pgp = recipient.pgp.as_ref();
+                smime = recipient.smime.as_ref();
             }
         }
+
+        if recipients.is_empty() {
+            return Err(ServiceError::NotFound("Unknown direction".to_string()));
+        }
     }
 
-    let message_text = if msg.allow_html {
+    // full_name comes mostly from system mails and it is implemented to be compatible with sendmail,
+    // the name from the From header on stdin is used when it is not set
+    let full_name =
+        ARGS.full_name
+            .clone()
+            .or(msg.headers.from.as_ref().and_then(|f| f.name.clone()));
+
+    // the sender must be the account from the relay, which sends the mail
+    message = message.from(Mailbox::new(full_name, CONFIG.sender(&relays).parse()?));
+
+    let text = if msg.allow_html {
         msg.text.clone()
     } else {
         msg.text._strip_tags()
     };
+    let mut subject = msg.subject.clone();
+    let mut message_text = text.clone();
 
-    if msg.send_copy {
-        let message_copy = message
-            .clone()
-            .to(msg.mail.parse()?)
-            .header(msg.content_type());
-        let mail = message_copy.body(message_text.clone())?;
-        send(mail).await?;
+    // the html is parsed only once, for the content type and for the plain text alternative
+    let mut plain_text = msg.html_dom().map(|dom| html_to_text(&dom));
+    let mut content_type = if plain_text.is_some() {
+        ContentType::TEXT_HTML
+    } else {
+        ContentType::TEXT_PLAIN
+    };
+
+    // custom form fields are added below the text, the alternative gets them as plain list
+    let fields_text = render(&msg.fields, field_config, plain_text.is_some());
+
+    if !fields_text.is_empty() {
+        message_text = format!("{message_text}\n\n{fields_text}");
+
+        if let Some(plain) = &mut plain_text {
+            let fields_plain = render(&msg.fields, field_config, false);
+            *plain = format!("{plain}\n\n{fields_plain}");
+        }
     }
 
+    let ctx = templates::context(&msg, &text);
+
+    // templates from the direction replace subject and body
+    if let Some(direction) = &msg.direction {
+        if let Some(s) = templates::render(direction, SUBJECT, &ctx)? {
+            subject = first_line(&s);
+        }
+
+        if let Some(html) = templates::render(direction, HTML, &ctx)? {
+            plain_text = Some(plain_alternative(&html));
+            message_text = html;
+            content_type = ContentType::TEXT_HTML;
+        } else if let Some(plain) = templates::render(direction, TEXT, &ctx)? {
+            plain_text = None;
+            message_text = plain;
+            content_type = ContentType::TEXT_PLAIN;
+        }
+    }
+
+    message = message.subject(subject);
+
     for rec in &recipients {
         message = message.to(rec.parse()?);
     }
 
+    // html bodies get a plain text alternative, for mail clients which show no html
+    let alternative =
+        plain_text.map(|plain| MultiPart::alternative_plain_html(plain, message_text.clone()));
+    let single = SinglePart::builder()
+        .header(content_type.clone())
+        .body(message_text.clone());
+
     // create multipart mail to support attachments
-    let mut part = MultiPart::mixed().singlepart(
-        SinglePart::builder()
-            .header(msg.content_type())
-            .body(message_text.clone()),
+    let part = match msg.attachment {
+        Some(files) => {
+            let mut part = match alternative {
+                Some(alternative) => MultiPart::mixed().multipart(alternative),
+                None => MultiPart::mixed().singlepart(single.clone()),
+            };
+
+            for file in files {
+                let content_type = ContentType::parse(mime_type(&file.1)).unwrap();
+                let attachment = Attachment::new(file.0).body(file.1, content_type);
+
+                part = part.singlepart(attachment);
+            }
+
+            Some(part)
+        }
+        None => alternative,
+    };
+
+    let mut body = match part {
+        Some(part) => MimeBody::Multi(part),
+        None => MimeBody::Single(single),
+    };
+
+    // copies from stdin are also encrypted, so the recipients need a certificate too
+    let mut readers = recipients.clone();
+    readers.extend(
+        msg.headers
+            .cc
+            .iter()
+            .chain(&msg.headers.bcc)
+            .map(|m| m.email.to_string()),
     );
 
-    // add attachments to mail if available
-    let mail = if let Some(files) = msg.attachment {
-        for file in files {
-            let mime_type = match infer::get(&file.1) {
-                Some(kind) => kind.mime_type(),
-                // application/octet-stream is the default mime type
-                None => "application/octet-stream",
-            };
+    // the whole body, with attachments, is encrypted for directions with PGP keys or S/MIME certificates
+    if let Some(pgp) = pgp {
+        body = MimeBody::Multi(encrypted_part(pgp, &readers, body.formatted()).await?);
+    } else if let Some(smime) = smime {
+        body = protect(smime, &readers, body)?;
+    }
+
+    let mut mail = match body {
+        MimeBody::Multi(part) => message.multipart(part)?,
+        MimeBody::Single(part) if pgp.is_some() || smime.is_some() => message.singlepart(part)?,
+        MimeBody::Single(_) => message.header(content_type).body(message_text)?,
+    };
+
+    for (name, value) in &msg.headers.extra {
+        match HeaderName::new_from_ascii(name.clone()) {
+            Ok(header_name) => mail
+                .headers_mut()
+                .insert_raw(HeaderValue::new(header_name, value.clone())),
+            Err(_) => warn!("Skip invalid header \"{name}\" from stdin"),
+        }
+    }
+
+    sign(&mut mail, msg.direction.as_deref());
+
+    if msg.quarantine {
+        return quarantine(&mail, &relays);
+    }
 
-            let content_type = ContentType::parse(mime_type).unwrap();
-            let attachment = Attachment::new(file.0).body(file.1, content_type);
+    let id = enqueue(&mail, &relays)?;
 
-            part = part.singlepart(attachment);
+    // the confirmation is only sent, when the staff has the message in the queue,
+    // spam suspects get no confirmation, otherwise mailpeter would send spam to foreign addresses
+    if let (Some(direction), Some(confirmation)) = (&msg.direction, confirmation) {
+        if msg.send_copy {
+            // the message is already queued, so a failed confirmation must not fail the request
+            if let Err(e) = send_confirmation(&msg.mail, direction, confirmation, &ctx, &relays) {
+                error!("Confirmation for message {id} failed: {e}");
+            }
         }
+    }
 
-        message.multipart(part)?
+    Ok(id)
+}
+
+/// Send the confirmation to the user, invalid or suppressed addresses get no confirmation.
+fn send_confirmation(
+    mail: &str,
+    direction: &str,
+    confirmation: &Confirmation,
+    ctx: &Value,
+    relays: &[String],
+) -> Result<(), ServiceError> {
+    let to: Mailbox = match mail.parse() {
+        Ok(to) => to,
+        Err(e) => {
+            warn!("No confirmation to invalid address <{mail}>: {e}");
+            return Ok(());
+        }
+    };
+
+    if CONFIG.suppressed(to.email.as_ref()) {
+        info!("No confirmation to suppressed address <{}>", to.email);
+        return Ok(());
+    }
+
+    let from = if confirmation.from.is_empty() {
+        CONFIG.sender(relays).parse()?
     } else {
-        message.header(msg.content_type()).body(message_text)?
+        confirmation.from.parse()?
     };
+    let (subject, body, html) = templates::render_confirmation(direction, ctx)?;
+    let mut message = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(first_line(&subject));
+
+    if !confirmation.reply_to.is_empty() {
+        message = message.reply_to(confirmation.reply_to.parse()?);
+    }
 
-    send(mail).await?;
+    let mut mail = if html {
+        message.multipart(MultiPart::alternative_plain_html(
+            plain_alternative(&body),
+            body,
+        ))?
+    } else {
+        message.header(ContentType::TEXT_PLAIN).body(body)?
+    };
+
+    sign(&mut mail, Some(direction));
+    enqueue(&mail, relays)?;
 
     Ok(())
 }
 
-/// Send mail from command line arguments
-pub async fn cli_message() -> Result<(), ServiceError> {
+/// Plain text alternative from a rendered html template, html which can not be parsed loses only its tags.
+fn plain_alternative(html: &str) -> String {
+    match Dom::parse(html) {
+        Ok(dom) => html_to_text(&dom),
+        Err(_) => html._strip_tags(),
+    }
+}
+
+/// Subjects from templates can only have one line.
+fn first_line(input: &str) -> String {
+    input.lines().next().unwrap_or_default().trim().to_string()
+}
+
+/// Send mail from command line arguments, returns the id from the queued message.
+pub async fn cli_message() -> Result<String, ServiceError> {
     let mut attachment = None;
     let mut recipient = ARGS.recipient.clone();
 
     if ARGS.text {
-        recipient = Some("--".to_string())
+        recipient = Some(HEADER_RECIPIENT.to_string())
     }
 
     if let Some(mut files) = ARGS.attachment.clone() {
@@ -280,11 +472,11 @@ pub async fn cli_message() -> Result<(), ServiceError> {
                 .to_string();
 
             // check if file is to big
-            if size > (CONFIG.max_attachment_size_mb * 1048576.0) as u64 {
+            if size > CONFIG.max_attachment_bytes() as u64 {
                 error!(
                     "Attachment to big! {size} > {max}",
                     size = size,
-                    max = CONFIG.max_attachment_size_mb * 1048576.0
+                    max = CONFIG.max_attachment_bytes()
                 );
 
                 return Err(ServiceError::Conflict("Attachment to big!".to_string()));
@@ -319,58 +511,46 @@ pub async fn cli_message() -> Result<(), ServiceError> {
                     attachment,
                 );
 
-                message_worker(msg).await?;
+                message_worker(msg).await
             } else {
-                let stdin = io::stdin();
-                let mut stdin_text = vec![];
-
-                // read stdin line by line and ignore lines that starts with IGNORE_LINES
-                // extract subject and recipient from stdin
-                for line in stdin.lock().lines() {
-                    let line = line?;
-                    if line.starts_with("Subject:") {
-                        subject = line.split_once(": ").unwrap().1.to_string();
-                    } else if line.starts_with("To:") {
-                        let res = line.split_once(": ").unwrap().1.to_string();
-                        if res.contains('@') {
-                            recipient = res;
-                        }
-                    } else if !(ignore_start(&line) || stdin_text.is_empty() && line.is_empty()) {
-                        stdin_text.push(line);
+                let mut input = vec![];
+                io::stdin().read_to_end(&mut input)?;
+
+                // complete MIME messages are relayed as they are, attachments from arguments need a new body
+                if attachment.is_none() {
+                    if let Some((envelope, raw)) = mime_message(&input, &recipient)? {
+                        return enqueue_raw(&envelope, &raw, &[DEFAULT_RELAY.to_string()]);
                     }
                 }
 
-                let msg = Msg::new(
-                    None,
-                    true,
-                    recipient.clone(),
-                    subject,
-                    stdin_text.join("\n"),
-                    attachment,
-                );
+                // subject and recipients from the message headers replace the arguments
+                let (headers, body) = parse_message(&String::from_utf8_lossy(&input));
 
-                trace!("Msg: {msg:?}");
+                if let Some(s) = &headers.subject {
+                    subject = s.clone();
+                }
 
-                message_worker(msg).await?;
-            }
-        }
-        None => {
-            return Err(ServiceError::Conflict(
-                "No mail recipient available!".to_string(),
-            ));
-        }
-    }
+                if !headers.to.is_empty() {
+                    recipient = headers
+                        .to
+                        .iter()
+                        .map(|m| m.email.to_string())
+                        .collect::<Vec<String>>()
+                        .join(",");
+                } else if recipient == HEADER_RECIPIENT {
+                    return Err(missing_recipient());
+                }
 
-    Ok(())
-}
+                let mut msg = Msg::new(None, true, recipient.clone(), subject, body, attachment);
+                msg.headers = headers;
 
-/// Check if line starts with IGNORE_LINES
-fn ignore_start(input: &str) -> bool {
-    for ignore_line in IGNORE_LINES.iter() {
-        if input.starts_with(ignore_line) {
-            return true;
+                trace!("Msg: {msg:?}");
+
+                message_worker(msg).await
+            }
         }
+        None => Err(ServiceError::Conflict(
+            "No mail recipient available!".to_string(),
+        )),
     }
-
-    false
 }
@@ -1,6 +1,22 @@
 pub mod arg_parser;
+pub mod attachments;
+pub mod auth;
+pub mod captcha;
+pub mod clamav;
 pub mod config;
+pub mod cors;
+pub mod dkim;
 pub mod errors;
+pub mod fields;
+pub mod form_guard;
+pub mod html_text;
 pub mod ip_extrator;
 pub mod logging;
 pub mod mailer;
+pub mod pgp;
+pub mod queue;
+pub mod sendmail;
+pub mod smime;
+pub mod spam;
+pub mod templates;
+pub mod transport;
@@ -0,0 +1,338 @@
+use std::{process::Stdio, str::from_utf8};
+
+use lettre::{
+    message::{
+        header::{ContentDisposition, ContentType},
+        Mailbox, MultiPart, SinglePart,
+    },
+    Address,
+};
+use log::error;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::utils::{config::Pgp, errors::ServiceError};
+
+/// Protocol from PGP/MIME encrypted messages, see RFC 3156
+const PGP_ENCRYPTED: &str = "application/pgp-encrypted";
+
+fn gpg(pgp: &Pgp) -> std::process::Command {
+    let mut cmd = std::process::Command::new(&pgp.gpg);
+    cmd.args(["--batch", "--no-tty", "--with-colons"]);
+
+    if pgp.keyring.is_empty() {
+        cmd.arg("--no-keyring");
+    } else {
+        cmd.args(["--homedir", &pgp.keyring]);
+    }
+
+    cmd
+}
+
+/// Mail addresses from keys which can encrypt, read from the **--with-colons** output from gpg.
+fn encryption_mails(listing: &str) -> Vec<String> {
+    let mut mails = vec![];
+    let mut can_encrypt = false;
+
+    for line in listing.lines() {
+        let columns: Vec<&str> = line.split(':').collect();
+
+        match columns.first() {
+            Some(&"pub") => can_encrypt = columns.get(11).is_some_and(|c| c.contains('E')),
+            Some(&"uid") if can_encrypt => {
+                let uid = columns.get(9).unwrap_or(&"").replace("\\x3a", ":");
+
+                if let Some(mail) = mail_address(&uid) {
+                    mails.push(mail);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    mails
+}
+
+/// Lower case mail address from a user id or recipient, like **Name <name@example.org>**.
+/// User ids are not always valid mailboxes, names with special characters have no quotes.
+fn mail_address(input: &str) -> Option<String> {
+    let bracketed = input
+        .rsplit_once('<')
+        .and_then(|(_, a)| a.strip_suffix('>'))
+        .unwrap_or(input);
+
+    input
+        .parse::<Mailbox>()
+        .map(|m| m.email)
+        .or_else(|_| bracketed.trim().parse::<Address>())
+        .ok()
+        .map(|a| a.to_string().to_lowercase())
+}
+
+/// Mail addresses from all public keys of the direction, which can be used for encryption.
+pub fn key_mails(pgp: &Pgp) -> Result<Vec<String>, ServiceError> {
+    let mut cmd = gpg(pgp);
+
+    if pgp.keyring.is_empty() {
+        cmd.arg("--show-keys").args(&pgp.keys);
+    } else {
+        cmd.arg("--list-keys");
+    }
+
+    let output = cmd
+        .output()
+        .map_err(|e| ServiceError::Conflict(format!("Can not run {}: {e}", pgp.gpg)))?;
+
+    if !output.status.success() {
+        return Err(ServiceError::Conflict(format!(
+            "Can not read PGP keys: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(encryption_mails(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Recipients without a public key, encrypted messages can not be sent to them.
+pub fn missing_keys<'a>(pgp: &Pgp, recipients: &'a [String]) -> Vec<&'a str> {
+    recipients
+        .iter()
+        .filter(|r| mail_address(r).is_none_or(|m| !pgp.key_mails.contains(&m)))
+        .map(|r| r.as_str())
+        .collect()
+}
+
+/// Encrypt data with gpg for all recipients, the result is ASCII armored.
+async fn encrypt(pgp: &Pgp, recipients: &[String], data: Vec<u8>) -> Result<String, ServiceError> {
+    let mut cmd = Command::from(gpg(pgp));
+    cmd.args(["--armor", "--trust-model", "always", "--encrypt"]);
+
+    if pgp.keyring.is_empty() {
+        for key in &pgp.keys {
+            cmd.args(["--recipient-file", key]);
+        }
+    } else {
+        for recipient in recipients {
+            cmd.args(["--recipient", &mail_address(recipient).unwrap_or_default()]);
+        }
+    }
+
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or(ServiceError::InternalServerError)?;
+    let writer = tokio::spawn(async move { stdin.write_all(&data).await });
+    let output = child.wait_with_output().await?;
+    writer
+        .await
+        .map_err(|_| ServiceError::InternalServerError)??;
+
+    match from_utf8(&output.stdout) {
+        Ok(armored) if output.status.success() => Ok(armored.to_string()),
+        _ => {
+            error!(
+                "PGP encryption failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+
+            Err(ServiceError::InternalServerError)
+        }
+    }
+}
+
+/// Encrypt the formatted body part, with attachments, to a PGP/MIME message.
+pub async fn encrypted_part(
+    pgp: &Pgp,
+    recipients: &[String],
+    formatted: Vec<u8>,
+) -> Result<MultiPart, ServiceError> {
+    let missing = missing_keys(pgp, recipients);
+
+    if !missing.is_empty() {
+        error!("No PGP key for: {}", missing.join(", "));
+
+        return Err(ServiceError::Conflict(format!(
+            "No PGP key for: {}",
+            missing.join(", ")
+        )));
+    }
+
+    let armored = encrypt(pgp, recipients, formatted).await?;
+
+    Ok(MultiPart::encrypted(PGP_ENCRYPTED.to_string())
+        .singlepart(
+            SinglePart::builder()
+                .header(ContentType::parse(PGP_ENCRYPTED).unwrap())
+                .body(String::from("Version: 1\r\n")),
+        )
+        .singlepart(
+            SinglePart::builder()
+                .header(
+                    ContentType::parse("application/octet-stream; name=\"encrypted.asc\"").unwrap(),
+                )
+                .header(ContentDisposition::inline_with_name("encrypted.asc"))
+                .body(armored),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use uuid::Uuid;
+
+    const LISTING: &str = "\
+pub:u:255:22:AAAAAAAAAAAAAAAA:1700000000:::u:::scESC::::::23::0:
+fpr:::::::::AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA:
+uid:u::::1700000000::HASH::Staff <Staff@Example.org>::::::::::0:
+uid:u::::1700000000::HASH::Support\\x3a Team <support@example.org>::::::::::0:
+sub:u:255:18:BBBBBBBBBBBBBBBB:1700000000::::::e::::::23:
+pub:u:255:22:CCCCCCCCCCCCCCCC:1700000000:::u:::scSC::::::23::0:
+uid:u::::1700000000::HASH::Sign Only <sign@example.org>::::::::::0:
+";
+
+    /// GnuPG home directory with a new key for **staff@example.org**, without passphrase.
+    fn keyring() -> Option<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("mailpeter-pgp-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).ok()?;
+
+        let status = std::process::Command::new("gpg")
+            .args(["--batch", "--homedir"])
+            .arg(&dir)
+            .args(["--passphrase", "", "--quick-gen-key"])
+            .args(["Staff <staff@example.org>", "default", "default", "never"])
+            .stderr(Stdio::null())
+            .status()
+            .ok()?;
+
+        status.success().then_some(dir)
+    }
+
+    fn remove_keyring(dir: &Path) {
+        let _ = std::process::Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(dir)
+            .args(["--kill", "gpg-agent"])
+            .status();
+
+        // the agent removes its sockets while it stops
+        for _ in 0..10 {
+            if fs::remove_dir_all(dir).is_ok() || !dir.exists() {
+                break;
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    }
+
+    fn pgp(keyring: &Path) -> Pgp {
+        let mut pgp: Pgp = toml::from_str(&format!("keyring = \"{}\"", keyring.display())).unwrap();
+        pgp.key_mails = key_mails(&pgp).unwrap();
+
+        pgp
+    }
+
+    #[test]
+    fn mails_from_listing() {
+        assert_eq!(
+            encryption_mails(LISTING),
+            vec!["staff@example.org", "support@example.org"]
+        );
+    }
+
+    #[test]
+    fn missing_recipient_keys() {
+        let pgp = Pgp {
+            keys: vec![],
+            keyring: String::new(),
+            gpg: "gpg".to_string(),
+            key_mails: encryption_mails(LISTING),
+        };
+        let recipients = vec![
+            "STAFF@example.org".to_string(),
+            "Support <support@example.org>".to_string(),
+            "sign@example.org".to_string(),
+            "invalid".to_string(),
+        ];
+
+        assert_eq!(
+            missing_keys(&pgp, &recipients),
+            vec!["sign@example.org", "invalid"]
+        );
+    }
+
+    #[tokio::test]
+    async fn pgp_mime_structure() {
+        let Some(dir) = keyring() else {
+            eprintln!("gpg is not available, skip PGP test");
+            return;
+        };
+        let pgp = pgp(&dir);
+
+        assert_eq!(pgp.key_mails, vec!["staff@example.org"]);
+
+        let body = b"Content-Type: text/plain\r\n\r\nSecret message\r\n".to_vec();
+        let part = encrypted_part(&pgp, &["staff@example.org".to_string()], body)
+            .await
+            .unwrap();
+        let formatted = String::from_utf8(part.formatted()).unwrap();
+
+        assert!(formatted.starts_with("Content-Type: multipart/encrypted;"));
+        assert!(formatted.contains("protocol=\"application/pgp-encrypted\""));
+
+        let boundary = formatted
+            .split("boundary=\"")
+            .nth(1)
+            .and_then(|b| b.split('"').next())
+            .unwrap();
+        let parts: Vec<&str> = formatted.split(&format!("--{boundary}")).skip(1).collect();
+        assert_eq!(parts.len(), 3, "two parts and the closing boundary");
+        assert!(parts[0].contains("Content-Type: application/pgp-encrypted\r\n"));
+        assert!(parts[0].contains("\r\n\r\nVersion: 1\r\n"));
+        assert!(parts[1].contains("Content-Type: application/octet-stream; name=\"encrypted.asc\""));
+        assert!(parts[1].contains("-----BEGIN PGP MESSAGE-----"));
+        assert!(!formatted.contains("Secret message"));
+
+        // the staff can decrypt it with their key
+        let armored = &parts[1][parts[1].find("-----BEGIN").unwrap()..];
+        let mut decrypt = std::process::Command::new("gpg")
+            .args(["--batch", "--homedir"])
+            .arg(&dir)
+            .arg("--decrypt")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut decrypt.stdin.take().unwrap(), armored.as_bytes()).unwrap();
+        let output = decrypt.wait_with_output().unwrap();
+
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Secret message"));
+
+        remove_keyring(&dir);
+    }
+
+    #[tokio::test]
+    async fn recipient_without_key() {
+        let Some(dir) = keyring() else {
+            eprintln!("gpg is not available, skip PGP test");
+            return;
+        };
+        let pgp = pgp(&dir);
+        let result = encrypted_part(&pgp, &["hr@example.org".to_string()], vec![]).await;
+
+        assert!(matches!(result, Err(ServiceError::Conflict(_))));
+
+        remove_keyring(&dir);
+    }
+}
@@ -0,0 +1,592 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use lettre::{address::Envelope, Message};
+use log::{debug, error, info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, time::sleep};
+use uuid::Uuid;
+
+use crate::utils::{
+    config::{Queue, DEFAULT_RELAY},
+    errors::ServiceError,
+    transport::{send, SendError},
+};
+use crate::CONFIG;
+
+/// How often the worker looks into the spool, when nobody wakes it up
+const POLL_INTERVAL: u64 = 10;
+
+lazy_static! {
+    static ref QUEUE_NOTIFY: Notify = Notify::new();
+    /// Reply code, like **(550)**, and enhanced status code, like **5.1.1**, from SMTP errors
+    static ref REPLY_CODES: Regex =
+        Regex::new(r"\(([45]\d\d)\)|(?:^|\s)([45]\.\d{1,3}\.\d{1,3})\b").unwrap();
+}
+
+/// Queue entry
+///
+/// Every accepted message is written to the spool directory as two files:
+/// * **{id}.eml** - The raw, formatted message
+/// * **{id}.json** - This struct, with the envelope and the delivery state
+///
+/// After delivery the json file moves to the **sent** folder and is kept for **status_retention_hours**,
+/// failed messages are moved with both files to the **deadletter** folder.
+/// Spam suspects are written to the **quarantine** folder and are not delivered.
+///
+/// While a worker delivers the message, the json file is renamed to **{id}.json.work**,
+/// so the same message can not be picked up twice.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QueueEntry {
+    pub id: String,
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    #[serde(default)]
+    pub relays: Vec<String>,
+    pub attempts: u32,
+    pub created: u64,
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+/// Delivery status, which is reported from the status endpoint.
+/// The last SMTP error has only the reply codes, without **status_errors** in the queue config,
+/// the full error would tell about the relays and their responses.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeliveryStatus {
+    pub id: String,
+    pub status: &'static str,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+impl QueueEntry {
+    fn envelope(&self) -> Result<Envelope, ServiceError> {
+        let from = match &self.from {
+            Some(f) => Some(f.parse()?),
+            None => None,
+        };
+        let mut to = vec![];
+
+        for rec in &self.to {
+            to.push(rec.parse()?);
+        }
+
+        Ok(Envelope::new(from, to)?)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn queue_dir() -> PathBuf {
+    Path::new(&CONFIG.queue.spool_dir).join("queue")
+}
+
+fn dead_letter_dir() -> PathBuf {
+    Path::new(&CONFIG.queue.spool_dir).join("deadletter")
+}
+
+fn quarantine_dir() -> PathBuf {
+    Path::new(&CONFIG.queue.spool_dir).join("quarantine")
+}
+
+fn sent_dir() -> PathBuf {
+    Path::new(&CONFIG.queue.spool_dir).join("sent")
+}
+
+/// Write file to a temporary path first and rename it afterwards,
+/// so a crash never leaves half written files in the spool.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), ServiceError> {
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, path)?;
+
+    Ok(())
+}
+
+fn write_entry(path: &Path, entry: &QueueEntry) -> Result<(), ServiceError> {
+    let json = serde_json::to_vec_pretty(entry).map_err(|e| {
+        error!("{e:?}");
+        ServiceError::InternalServerError
+    })?;
+
+    write_atomic(path, &json)
+}
+
+/// Calculate the delay before the next delivery attempt, it doubles with every attempt
+/// and is capped at **retry_max_seconds**.
+fn backoff(queue: &Queue, attempts: u32) -> u64 {
+    let factor = 2_u64.saturating_pow(attempts.saturating_sub(1));
+
+    queue
+        .retry_base_seconds
+        .saturating_mul(factor)
+        .min(queue.retry_max_seconds)
+}
+
+/// Write message with its queue entry to a spool folder.
+fn write_message(
+    dir: &Path,
+    envelope: &Envelope,
+    raw: &[u8],
+    relays: &[String],
+) -> Result<String, ServiceError> {
+    fs::create_dir_all(dir)?;
+
+    let id = Uuid::new_v4().to_string();
+    let entry = QueueEntry {
+        id: id.clone(),
+        from: envelope.from().map(|f| f.to_string()),
+        to: envelope.to().iter().map(|t| t.to_string()).collect(),
+        relays: relays.to_vec(),
+        attempts: 0,
+        created: now(),
+        next_attempt: 0,
+        last_error: None,
+    };
+
+    // the message must exist before the entry, otherwise the worker could find an entry without message
+    write_atomic(&dir.join(format!("{id}.eml")), raw)?;
+    write_entry(&dir.join(format!("{id}.json")), &entry)?;
+
+    Ok(id)
+}
+
+/// Write message to the spool directory and wake up the queue worker.
+/// The relays are tried in the given order, when the message gets delivered.
+/// Returns the id from the queue entry.
+pub fn enqueue(message: &Message, relays: &[String]) -> Result<String, ServiceError> {
+    enqueue_raw(message.envelope(), &message.formatted(), relays)
+}
+
+/// Write an already formatted message to the spool directory, like **enqueue**.
+pub fn enqueue_raw(
+    envelope: &Envelope,
+    raw: &[u8],
+    relays: &[String],
+) -> Result<String, ServiceError> {
+    let id = write_message(&queue_dir(), envelope, raw, relays)?;
+
+    debug!("Queued message {id}");
+
+    QUEUE_NOTIFY.notify_one();
+
+    Ok(id)
+}
+
+/// Write message to the quarantine folder, it is not delivered.
+/// To release it, move both files to the queue folder.
+pub fn quarantine(message: &Message, relays: &[String]) -> Result<String, ServiceError> {
+    let id = write_message(
+        &quarantine_dir(),
+        message.envelope(),
+        &message.formatted(),
+        relays,
+    )?;
+
+    info!("Message {id} moved to quarantine");
+
+    Ok(id)
+}
+
+/// Try to deliver one queue entry.
+///
+/// On success the message gets removed from the spool. On failure the attempts are counted up and
+/// the next attempt is scheduled with exponential backoff. Permanent SMTP errors, or reaching
+/// **max_attempts**, moves the message to the dead letter folder.
+async fn deliver(work_path: &Path) -> Result<(), ServiceError> {
+    let dir = queue_dir();
+    let mut entry: QueueEntry = serde_json::from_slice(&fs::read(work_path)?).map_err(|e| {
+        error!("Broken queue entry {work_path:?}: {e}");
+        ServiceError::InternalServerError
+    })?;
+    let eml_path = dir.join(format!("{}.eml", entry.id));
+    let json_path = dir.join(format!("{}.json", entry.id));
+    let dead_dir = dead_letter_dir();
+
+    // without message the entry can never be delivered, retrying it would only fill the log
+    let raw = match fs::read(&eml_path) {
+        Ok(raw) => raw,
+        Err(e) => {
+            error!(
+                "Message {} can not be read, move it to dead letter folder: {e}",
+                entry.id
+            );
+
+            entry.last_error = Some(format!("Can not read message: {e}"));
+            fs::create_dir_all(&dead_dir)?;
+            write_entry(&dead_dir.join(format!("{}.json", entry.id)), &entry)?;
+            fs::remove_file(work_path)?;
+
+            return Ok(());
+        }
+    };
+
+    // entries from older versions have no relays
+    if entry.relays.is_empty() {
+        entry.relays.push(DEFAULT_RELAY.to_string());
+    }
+
+    let result = match entry.envelope() {
+        Ok(envelope) => send(&entry.relays, &envelope, &raw).await,
+        Err(e) => Err(SendError {
+            message: e.to_string(),
+            permanent: true,
+        }),
+    };
+
+    entry.attempts += 1;
+
+    match result {
+        Ok(_) => {
+            info!("Message {} delivered", entry.id);
+
+            // keep the entry for the status endpoint, the message itself is not needed anymore
+            let sent = sent_dir();
+            fs::create_dir_all(&sent)?;
+            write_entry(&sent.join(format!("{}.json", entry.id)), &entry)?;
+
+            fs::remove_file(&eml_path)?;
+            fs::remove_file(work_path)?;
+        }
+        Err(SendError {
+            message: err,
+            permanent,
+        }) => {
+            entry.last_error = Some(err.clone());
+
+            if permanent || entry.attempts >= CONFIG.queue.max_attempts {
+                fs::create_dir_all(&dead_dir)?;
+
+                error!(
+                    "Message {} failed after {} attempt(s), move it to dead letter folder: {err}",
+                    entry.id, entry.attempts
+                );
+
+                fs::rename(&eml_path, dead_dir.join(format!("{}.eml", entry.id)))?;
+                write_entry(&dead_dir.join(format!("{}.json", entry.id)), &entry)?;
+                fs::remove_file(work_path)?;
+            } else {
+                let delay = backoff(&CONFIG.queue, entry.attempts);
+                entry.next_attempt = now() + delay;
+
+                warn!(
+                    "Message {} attempt {} failed, retry in {delay}s: {err}",
+                    entry.id, entry.attempts
+                );
+
+                write_entry(&json_path, &entry)?;
+                fs::remove_file(work_path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Claim the entry by renaming it to **{id}.json.work**.
+/// Returns **None** when the rename fails, then another process has it already.
+fn claim(path: &Path) -> Option<PathBuf> {
+    let work_path = path.with_extension("json.work");
+
+    fs::rename(path, &work_path).ok().map(|_| work_path)
+}
+
+/// Go through the spool and deliver all messages which are due.
+/// Returns the time from the next scheduled attempt, if there is one.
+pub async fn process_queue() -> Result<Option<u64>, ServiceError> {
+    let dir = queue_dir();
+    let mut next_due: Option<u64> = None;
+
+    if !dir.is_dir() {
+        return Ok(next_due);
+    }
+
+    let mut entries = vec![];
+
+    for item in fs::read_dir(&dir)? {
+        let path = item?.path();
+
+        if path.extension().and_then(|e| e.to_str()) == Some("json") {
+            entries.push(path);
+        }
+    }
+
+    entries.sort();
+
+    for path in entries {
+        let entry: QueueEntry = match fs::read(&path)
+            .ok()
+            .and_then(|c| serde_json::from_slice(&c).ok())
+        {
+            Some(e) => e,
+            None => {
+                error!("Can not read queue entry: {path:?}");
+                continue;
+            }
+        };
+
+        if entry.next_attempt > now() {
+            next_due = Some(next_due.map_or(entry.next_attempt, |n| n.min(entry.next_attempt)));
+            continue;
+        }
+
+        let Some(work_path) = claim(&path) else {
+            continue;
+        };
+
+        if let Err(e) = deliver(&work_path).await {
+            error!("Deliver {} failed: {e}", entry.id);
+
+            // give the entry back to the queue
+            let _ = fs::rename(&work_path, &path);
+        }
+    }
+
+    Ok(next_due)
+}
+
+/// Error for the status endpoint, only with the SMTP reply codes, like **550 5.1.1**.
+fn redact_error(error: &str) -> String {
+    let codes: Vec<&str> = REPLY_CODES
+        .captures_iter(error)
+        .filter_map(|c| c.get(1).or(c.get(2)))
+        .map(|m| m.as_str())
+        .collect();
+
+    if codes.is_empty() {
+        "delivery error".to_string()
+    } else {
+        codes.join(" ")
+    }
+}
+
+/// Status from an entry in the queue folder, entries with failed attempts are **retrying**.
+fn queue_status(entry: &QueueEntry) -> &'static str {
+    if entry.attempts > 0 {
+        "retrying"
+    } else {
+        "queued"
+    }
+}
+
+/// Look up the delivery status from a message.
+///
+/// The worker renames entries while it delivers them, so a lookup can miss the entry between
+/// two renames. Then the folders are checked a second time.
+pub fn delivery_status(id: &Uuid) -> Option<DeliveryStatus> {
+    let queue = queue_dir();
+    // entries in the queue get their status from the attempts
+    let candidates = [
+        (queue.join(format!("{id}.json.work")), None),
+        (queue.join(format!("{id}.json")), None),
+        // don't tell spammers, that their message is in quarantine
+        (quarantine_dir().join(format!("{id}.json")), Some("queued")),
+        (sent_dir().join(format!("{id}.json")), Some("sent")),
+        (dead_letter_dir().join(format!("{id}.json")), Some("failed")),
+    ];
+
+    for _ in 0..2 {
+        for (path, status) in &candidates {
+            if let Some(entry) = fs::read(path)
+                .ok()
+                .and_then(|c| serde_json::from_slice::<QueueEntry>(&c).ok())
+            {
+                let last_error = if CONFIG.queue.status_errors {
+                    entry.last_error.clone()
+                } else {
+                    entry.last_error.as_deref().map(redact_error)
+                };
+
+                return Some(DeliveryStatus {
+                    status: status.unwrap_or_else(|| queue_status(&entry)),
+                    id: entry.id,
+                    attempts: entry.attempts,
+                    last_error,
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/// Remove status entries from sent messages, which are older then **status_retention_hours**.
+fn cleanup_sent() -> Result<(), ServiceError> {
+    let dir = sent_dir();
+
+    if !dir.is_dir() {
+        return Ok(());
+    }
+
+    let max_age = Duration::from_secs(CONFIG.queue.status_retention_hours * 3600);
+
+    for item in fs::read_dir(&dir)? {
+        let path = item?.path();
+        let age = fs::metadata(&path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+
+        if age > max_age {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Entries which are still claimed from a previous run, are given back to the queue.
+fn recover_claimed(dir: &Path) -> Result<(), ServiceError> {
+    fs::create_dir_all(dir)?;
+
+    for item in fs::read_dir(dir)? {
+        let path = item?.path();
+
+        if path.to_string_lossy().ends_with(".json.work") {
+            warn!("Recover unfinished queue entry: {path:?}");
+
+            fs::rename(&path, path.with_extension(""))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Background worker, which drains the spool directory.
+///
+/// The worker runs through the queue when the next retry is due, at least every few seconds,
+/// or directly when a new message got queued.
+pub async fn queue_worker() {
+    if let Err(e) = recover_claimed(&queue_dir()) {
+        error!("Can not initialize spool directory: {e}");
+    }
+
+    loop {
+        let mut wait = POLL_INTERVAL;
+
+        match process_queue().await {
+            Ok(Some(next_due)) => wait = next_due.saturating_sub(now()).clamp(1, POLL_INTERVAL),
+            Ok(None) => {}
+            Err(e) => error!("Process queue: {e}"),
+        }
+
+        if let Err(e) = cleanup_sent() {
+            error!("Cleanup sent entries: {e}");
+        }
+
+        tokio::select! {
+            _ = QUEUE_NOTIFY.notified() => {}
+            _ = sleep(Duration::from_secs(wait)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(attempts: u32) -> QueueEntry {
+        QueueEntry {
+            id: Uuid::new_v4().to_string(),
+            from: Some("info@example.org".to_string()),
+            to: vec!["staff@example.org".to_string()],
+            relays: vec![DEFAULT_RELAY.to_string()],
+            attempts,
+            created: 0,
+            next_attempt: 0,
+            last_error: None,
+        }
+    }
+
+    fn spool() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mailpeter-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn backoff_doubles_until_maximum() {
+        let queue = Queue {
+            retry_base_seconds: 30,
+            retry_max_seconds: 200,
+            ..Queue::default()
+        };
+
+        assert_eq!(backoff(&queue, 1), 30);
+        assert_eq!(backoff(&queue, 2), 60);
+        assert_eq!(backoff(&queue, 3), 120);
+        assert_eq!(backoff(&queue, 4), 200);
+        assert_eq!(backoff(&queue, u32::MAX), 200);
+    }
+
+    #[test]
+    fn entry_is_claimed_only_once() {
+        let dir = spool();
+        let path = dir.join("one.json");
+        write_entry(&path, &entry(0)).unwrap();
+
+        let work_path = claim(&path).unwrap();
+
+        assert!(work_path.to_string_lossy().ends_with("one.json.work"));
+        assert!(!path.exists());
+        assert!(claim(&path).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn claimed_entry_is_recovered() {
+        let dir = spool();
+        let path = dir.join("two.json");
+        write_entry(&path, &entry(1)).unwrap();
+        claim(&path).unwrap();
+
+        recover_claimed(&dir).unwrap();
+
+        assert!(path.exists());
+        assert!(claim(&path).is_some());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn errors_only_with_reply_codes() {
+        assert_eq!(
+            redact_error(
+                "permanent error (550): 5.1.1 <x@example.org>: user unknown at mx.example.org"
+            ),
+            "550 5.1.1"
+        );
+        assert_eq!(
+            redact_error("Connection refused to 10.4.0.25:587"),
+            "delivery error"
+        );
+    }
+
+    #[test]
+    fn status_from_attempts() {
+        assert_eq!(queue_status(&entry(0)), "queued");
+        assert_eq!(queue_status(&entry(2)), "retrying");
+    }
+
+    #[test]
+    fn envelope_from_entry() {
+        let envelope = entry(0).envelope().unwrap();
+
+        assert_eq!(envelope.from().unwrap().to_string(), "info@example.org");
+        assert_eq!(envelope.to()[0].to_string(), "staff@example.org");
+    }
+}
@@ -0,0 +1,578 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lettre::{
+    address::Envelope,
+    message::{
+        header::{ContentTransferEncoding, Header, HeaderName, HeaderValue},
+        Body, Mailbox, SinglePart,
+    },
+    Message,
+};
+use log::debug;
+use mail_parser::{Address, MessageParser, MimeHeaders};
+
+use crate::utils::{dkim::sign, errors::ServiceError, mailer::MimeBody, smime::protect};
+use crate::{ARGS, CONFIG};
+
+/// Placeholder for the recipient, when the recipients come from the message headers (**-t**)
+pub const HEADER_RECIPIENT: &str = "--";
+
+/// Headers from stdin, which are not taken over to the mail
+const IGNORE_HEADERS: [&str; 1] = ["X-Cron-Env"];
+
+/// Headers from a message, text which starts with other names is taken as body
+const MESSAGE_HEADERS: [&str; 11] = [
+    "Bcc",
+    "Cc",
+    "Content-Type",
+    "Date",
+    "From",
+    "Message-ID",
+    "MIME-Version",
+    "Reply-To",
+    "Sender",
+    "Subject",
+    "To",
+];
+
+/// Headers which are set or dropped by mailpeter, when a MIME message from stdin is relayed
+const REPLACED_HEADERS: [&str; 4] = ["Bcc", "From", "Sender", "To"];
+
+/// Headers which describe the body, with S/MIME they move into the protected entity
+const CONTENT_HEADERS: [&str; 3] = ["Content-Transfer-Encoding", "Content-Type", "MIME-Version"];
+
+/// Headers from a message on stdin, like sendmail gets it from cron or other system tools
+///
+/// * **subject** - The decoded subject
+/// * **from** - The original sender, only the name is used, the address comes from the config
+/// * **to**, **cc**, **bcc**, **reply_to** - Mailboxes from the address headers
+/// * **date** - The original date
+/// * **message_id** - The original message id, with angle brackets
+/// * **extra** - All **X-** headers, with unfolded values
+#[derive(Clone, Debug, Default)]
+pub struct MailHeaders {
+    pub subject: Option<String>,
+    pub from: Option<Mailbox>,
+    pub to: Vec<Mailbox>,
+    pub cc: Vec<Mailbox>,
+    pub bcc: Vec<Mailbox>,
+    pub reply_to: Vec<Mailbox>,
+    pub date: Option<SystemTime>,
+    pub message_id: Option<String>,
+    pub extra: Vec<(String, String)>,
+}
+
+/// Field name from a header line, see RFC 5322 section 2.2.
+fn header_name(line: &str) -> Option<&str> {
+    line.split_once(':')
+        .map(|(name, _)| name)
+        .filter(|name| !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic()))
+}
+
+/// Extension headers, like **X-Mailer**, which are taken over to the mail.
+fn is_extension(name: &str) -> bool {
+    name.get(..2).is_some_and(|p| p.eq_ignore_ascii_case("X-"))
+}
+
+/// Split the input into header block and body. The header block ends at the first blank line,
+/// text without header block, like **Warning: disk full** from a script, is only body.
+fn split_header(input: &str) -> (&str, &str) {
+    let mut pos = 0;
+    let mut message_header = false;
+
+    for line in input.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\r', '\n']);
+
+        if content.is_empty() {
+            if message_header {
+                return (&input[..pos], &input[pos + line.len()..]);
+            }
+
+            break;
+        }
+
+        if pos == 0 || !content.starts_with([' ', '\t']) {
+            match header_name(content) {
+                Some(name) => {
+                    message_header |= is_extension(name)
+                        || MESSAGE_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name));
+                }
+                None => break,
+            }
+        }
+
+        pos += line.len();
+    }
+
+    if pos == input.len() && message_header {
+        return (input, "");
+    }
+
+    ("", input)
+}
+
+/// Mailboxes from an address header, addresses without domain are skipped.
+fn mailboxes(address: Option<&Address>) -> Vec<Mailbox> {
+    address
+        .map(|a| a.iter().collect::<Vec<_>>())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|addr| {
+            let mail = addr.address()?;
+
+            match mail.parse() {
+                Ok(email) => Some(Mailbox::new(addr.name().map(str::to_string), email)),
+                Err(e) => {
+                    debug!("Skip address <{mail}> from stdin: {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Parse a message from stdin, the headers are decoded and unfolded, the body stays untouched.
+pub fn parse_message(input: &str) -> (MailHeaders, String) {
+    let (header, body) = split_header(input);
+    let mut headers = MailHeaders::default();
+
+    if header.is_empty() {
+        return (headers, body.to_string());
+    }
+
+    // the parser needs the blank line, to know where the headers end
+    let raw = format!("{header}\n");
+
+    if let Some(message) = MessageParser::default().parse_headers(raw.as_bytes()) {
+        headers.subject = message.subject().map(str::to_string);
+        headers.from = mailboxes(message.from()).into_iter().next();
+        headers.to = mailboxes(message.to());
+        headers.cc = mailboxes(message.cc());
+        headers.bcc = mailboxes(message.bcc());
+        headers.reply_to = mailboxes(message.reply_to());
+        headers.date = message.date().and_then(|d| {
+            u64::try_from(d.to_timestamp())
+                .ok()
+                .map(|s| UNIX_EPOCH + Duration::from_secs(s))
+        });
+        headers.message_id = message.message_id().map(|id| format!("<{id}>"));
+        headers.extra = message
+            .headers_raw()
+            .filter(|(name, _)| {
+                is_extension(name) && !IGNORE_HEADERS.iter().any(|i| i.eq_ignore_ascii_case(name))
+            })
+            .map(|(name, value)| {
+                let value = value.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+                (name.to_string(), value)
+            })
+            .collect();
+    }
+
+    (headers, body.to_string())
+}
+
+/// Messages with **MIME-Version** header, which can be parsed. Multipart messages need a boundary
+/// and at least one part.
+fn is_mime(input: &[u8]) -> bool {
+    let Some(message) = MessageParser::default().parse(input) else {
+        return false;
+    };
+    let root = message.root_part();
+
+    if message.mime_version().is_empty() || message.parts.iter().any(|p| p.is_encoding_problem) {
+        return false;
+    }
+
+    match root.content_type() {
+        Some(c) if c.ctype().eq_ignore_ascii_case("multipart") => {
+            c.attribute("boundary").is_some() && root.sub_parts().is_some_and(|p| !p.is_empty())
+        }
+        _ => true,
+    }
+}
+
+/// Split the raw input at the first blank line.
+fn split_raw(input: &[u8]) -> (&[u8], &[u8]) {
+    let mut pos = 0;
+
+    for line in input.split_inclusive(|b| *b == b'\n') {
+        if line.iter().all(|b| *b == b'\r' || *b == b'\n') {
+            return (&input[..pos], &input[pos + line.len()..]);
+        }
+
+        pos += line.len();
+    }
+
+    (input, &[])
+}
+
+/// Line endings in mails are always CRLF.
+fn crlf(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+
+    for line in input.split_inclusive(|b| *b == b'\n') {
+        match line.strip_suffix(b"\n") {
+            Some(content) => {
+                output.extend_from_slice(content.strip_suffix(b"\r").unwrap_or(content));
+                output.extend_from_slice(b"\r\n");
+            }
+            None => output.extend_from_slice(line),
+        }
+    }
+
+    output
+}
+
+/// Header fields from the header block, folded lines stay folded.
+fn header_fields(header: &str) -> Vec<(&str, String)> {
+    let mut fields: Vec<(&str, String)> = vec![];
+
+    for line in header.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push_str("\r\n");
+                value.push_str(line);
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name, value.trim_start().to_string()));
+        }
+    }
+
+    fields
+}
+
+/// Content type from a message on stdin, it is taken over with its original value and parameters.
+#[derive(Clone)]
+struct RawContentType(String);
+
+impl Header for RawContentType {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("Content-Type")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self(s.to_string()))
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::dangerous_new_pre_encoded(Self::name(), self.0.clone(), self.0.clone())
+    }
+}
+
+/// The body with its content headers as one MIME entity, which can be signed and encrypted with S/MIME.
+/// The parts inside stay unchanged.
+fn mime_entity(
+    fields: &[(&str, String)],
+    body: Vec<u8>,
+    encoding: ContentTransferEncoding,
+) -> SinglePart {
+    let field = |name: &str| {
+        fields
+            .iter()
+            .rev()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    let encoding = field("Content-Transfer-Encoding")
+        .and_then(|e| ContentTransferEncoding::parse(e.trim()).ok())
+        .unwrap_or(encoding);
+    let content_type = field("Content-Type").unwrap_or("text/plain; charset=us-ascii");
+
+    SinglePart::builder()
+        .header(RawContentType(content_type.to_string()))
+        .body(Body::dangerous_pre_encoded(body, encoding))
+}
+
+/// Error for messages from stdin, which have no recipient in the arguments and no **To** header.
+pub fn missing_recipient() -> ServiceError {
+    ServiceError::Conflict("No mail recipient available, the message has no To header!".to_string())
+}
+
+/// Write repeated header fields, like **Received** or **Comments**, before the last field with the same name.
+/// Only the last field is in the headers from the message, so DKIM verifiers, which take the last field, find the signed one.
+fn insert_repeated(formatted: &[u8], repeated: &[(HeaderName, String)]) -> Vec<u8> {
+    let (header, body) = split_raw(formatted);
+    let mut output = Vec::with_capacity(formatted.len());
+    let mut written = vec![];
+
+    for line in header.split_inclusive(|b| *b == b'\n') {
+        let line_name = String::from_utf8_lossy(line)
+            .split_once(':')
+            .map(|(name, _)| name.to_string())
+            .filter(|_| !line.starts_with(b" ") && !line.starts_with(b"\t"));
+
+        if let Some(line_name) = line_name.filter(|n| !written.contains(n)) {
+            for (name, value) in repeated
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case(&line_name))
+            {
+                output.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+            }
+
+            written.push(line_name);
+        }
+
+        output.extend_from_slice(line);
+    }
+
+    output.extend_from_slice(b"\r\n");
+    output.extend_from_slice(body);
+
+    output
+}
+
+/// Complete MIME message from stdin, like from mutt or logwatch, which is relayed without changes.
+/// Only the sender is replaced with the address from the relay, **Sender** is dropped and **Bcc** goes to the envelope.
+/// Recipients come from **To**, **Cc** and **Bcc**, or from the arguments, when the message has no **To**.
+/// With S/MIME in the mail config, the body is signed and encrypted with its content headers.
+///
+/// Returns the envelope and the formatted message, or **None** when the input is no valid MIME message.
+pub fn mime_message(
+    input: &[u8],
+    recipient: &str,
+) -> Result<Option<(Envelope, Vec<u8>)>, ServiceError> {
+    if !is_mime(input) {
+        return Ok(None);
+    }
+
+    let (raw_header, raw_body) = split_raw(input);
+    let header = String::from_utf8_lossy(raw_header);
+    let raw_fields = header_fields(&header);
+    let (headers, _) = parse_message(&header);
+    let smime = CONFIG.mail.smime.as_ref();
+    let name = ARGS.full_name.clone().or(headers.from.and_then(|f| f.name));
+    let from = Mailbox::new(name, CONFIG.mail.relay.sender().parse()?);
+    let mut message = Message::builder().from(from.clone());
+    let mut to = headers.to;
+
+    if to.is_empty() {
+        if recipient == HEADER_RECIPIENT {
+            return Err(missing_recipient());
+        }
+
+        for rec in recipient.split(',') {
+            to.push(rec.trim().parse()?);
+        }
+    }
+
+    for mbox in &to {
+        message = message.to(mbox.clone());
+    }
+
+    let envelope = Envelope::new(
+        Some(from.email),
+        to.into_iter()
+            .chain(headers.cc)
+            .chain(headers.bcc)
+            .map(|m| m.email)
+            .collect(),
+    )?;
+    let readers: Vec<String> = envelope.to().iter().map(|a| a.to_string()).collect();
+    let message = message.envelope(envelope);
+    let encoding = if raw_body.is_ascii() {
+        ContentTransferEncoding::SevenBit
+    } else {
+        ContentTransferEncoding::EightBit
+    };
+
+    // with S/MIME the original body is signed and encrypted as one entity, like the bodies from the API
+    let mut mail = match smime {
+        Some(smime) => {
+            let entity = mime_entity(&raw_fields, crlf(raw_body), encoding);
+
+            match protect(smime, &readers, MimeBody::Single(entity))? {
+                MimeBody::Multi(part) => message.multipart(part)?,
+                MimeBody::Single(part) => message.singlepart(part)?,
+            }
+        }
+        None => message.body(Body::dangerous_pre_encoded(crlf(raw_body), encoding))?,
+    };
+
+    let mut fields: Vec<(HeaderName, String)> = vec![];
+
+    for (name, value) in raw_fields {
+        let replaced = REPLACED_HEADERS
+            .iter()
+            .chain(&IGNORE_HEADERS)
+            .chain(smime.map_or(&[][..], |_| &CONTENT_HEADERS[..]))
+            .any(|h| h.eq_ignore_ascii_case(name));
+
+        if replaced {
+            continue;
+        }
+
+        match HeaderName::new_from_ascii(name.to_string()) {
+            Ok(header_name) => fields.push((header_name, value)),
+            Err(_) => debug!("Skip invalid header \"{name}\" from stdin"),
+        }
+    }
+
+    // lettre keeps only one header per name, so it gets the last field and the others are written separately
+    let mut repeated = vec![];
+
+    for (i, (name, value)) in fields.iter().enumerate() {
+        if fields[i + 1..]
+            .iter()
+            .any(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            repeated.push((name.clone(), value.clone()));
+        } else {
+            mail.headers_mut()
+                .insert_raw(HeaderValue::dangerous_new_pre_encoded(
+                    name.clone(),
+                    value.clone(),
+                    value.clone(),
+                ));
+        }
+    }
+
+    sign(&mut mail, None);
+
+    Ok(Some((
+        mail.envelope().clone(),
+        insert_repeated(&mail.formatted(), &repeated),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_block_until_blank_line() {
+        let input = "Subject: Disk\nX-Cron-Job: backup\n\nBody line\n\nmore";
+
+        assert_eq!(
+            split_header(input),
+            ("Subject: Disk\nX-Cron-Job: backup\n", "Body line\n\nmore")
+        );
+    }
+
+    #[test]
+    fn text_without_headers_is_body() {
+        for input in [
+            "Warning: disk full\n\n/dev/sda1 99%",
+            "Hello world",
+            "Subject: no blank line\nthis is text",
+            "\nSubject: after blank line",
+        ] {
+            assert_eq!(split_header(input), ("", input));
+        }
+    }
+
+    #[test]
+    fn header_only_input() {
+        assert_eq!(split_header("Subject: only\n"), ("Subject: only\n", ""));
+    }
+
+    #[test]
+    fn folded_and_encoded_headers() {
+        let input = "Subject: =?utf-8?q?Gr=C3=BC=C3=9Fe?= from\n cron\nFrom: \"Cron Daemon\" <root@host>\n\
+                     To: Admin <admin@example.org>, ops@example.org\nCc: broken\nX-Cron-Env: <SHELL=/bin/sh>\n\
+                     X-Job: nightly\n\tbackup\nMessage-ID: <1@host>\n\nbody\n";
+        let (headers, body) = parse_message(input);
+
+        assert_eq!(headers.subject.as_deref(), Some("Grüße from cron"));
+        assert_eq!(
+            headers.from.and_then(|f| f.name).as_deref(),
+            Some("Cron Daemon")
+        );
+        assert_eq!(
+            headers
+                .to
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<String>>(),
+            ["Admin <admin@example.org>", "ops@example.org"]
+        );
+        assert!(headers.cc.is_empty());
+        assert_eq!(headers.message_id.as_deref(), Some("<1@host>"));
+        assert_eq!(
+            headers.extra,
+            [("X-Job".to_string(), "nightly backup".to_string())]
+        );
+        assert_eq!(body, "body\n");
+    }
+
+    #[test]
+    fn repeated_headers_before_last_field() {
+        let formatted = b"From: a@example.org\r\nReceived: last\r\nSubject: s\r\n\r\nbody\r\n";
+        let repeated = vec![
+            (
+                HeaderName::new_from_ascii_str("Received"),
+                "first".to_string(),
+            ),
+            (
+                HeaderName::new_from_ascii_str("Received"),
+                "second\r\n folded".to_string(),
+            ),
+        ];
+
+        assert_eq!(
+            String::from_utf8(insert_repeated(formatted, &repeated)).unwrap(),
+            "From: a@example.org\r\nReceived: first\r\nReceived: second\r\n folded\r\n\
+             Received: last\r\nSubject: s\r\n\r\nbody\r\n"
+        );
+    }
+
+    #[test]
+    fn mime_messages() {
+        let multipart =
+            b"MIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=\"XYZ\"\r\n\r\n\
+                          --XYZ\r\nContent-Type: text/plain\r\n\r\nhello\r\n--XYZ--\r\n";
+
+        assert!(is_mime(multipart));
+        assert!(is_mime(
+            b"MIME-Version: 1.0\nContent-Type: text/plain\n\nhello\n"
+        ));
+        assert!(!is_mime(b"Subject: plain\n\nhello\n"));
+        assert!(!is_mime(
+            b"MIME-Version: 1.0\nContent-Type: multipart/mixed\n\nhello\n"
+        ));
+        assert!(!is_mime(
+            b"MIME-Version: 1.0\nContent-Type: multipart/mixed; boundary=\"XYZ\"\n\nno parts\n"
+        ));
+    }
+
+    #[test]
+    fn raw_header_and_fields() {
+        let input = b"Subject: one\r\n two\nX-Tag: a\nX-Tag: b\n\r\nbody\nline\n";
+        let (header, body) = split_raw(input);
+
+        assert_eq!(body, b"body\nline\n");
+        assert_eq!(crlf(body), b"body\r\nline\r\n");
+        assert_eq!(crlf(b"no end"), b"no end");
+        assert_eq!(
+            header_fields(&String::from_utf8_lossy(header)),
+            [
+                ("Subject", "one\r\n two".to_string()),
+                ("X-Tag", "a".to_string()),
+                ("X-Tag", "b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn entity_keeps_content_headers() {
+        let fields = vec![
+            ("Subject", "s".to_string()),
+            (
+                "Content-Type",
+                "multipart/mixed;\r\n boundary=\"XYZ\"".to_string(),
+            ),
+            ("Content-Transfer-Encoding", "8bit".to_string()),
+        ];
+        let entity = mime_entity(
+            &fields,
+            b"--XYZ\r\n\r\nhi\r\n--XYZ--\r\n".to_vec(),
+            ContentTransferEncoding::SevenBit,
+        );
+
+        assert_eq!(
+            String::from_utf8(entity.formatted()).unwrap(),
+            "Content-Type: multipart/mixed;\r\n boundary=\"XYZ\"\r\nContent-Transfer-Encoding: 8bit\r\n\r\n\
+             --XYZ\r\n\r\nhi\r\n--XYZ--\r\n\r\n"
+        );
+    }
+}
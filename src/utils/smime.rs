@@ -0,0 +1,235 @@
+use std::{fmt, fs};
+
+use lettre::message::{
+    header::{ContentDisposition, ContentType},
+    Mailbox, MultiPart, SinglePart,
+};
+use log::error;
+use openssl::{
+    nid::Nid,
+    pkcs12::Pkcs12,
+    pkcs7::{Pkcs7, Pkcs7Flags},
+    pkey::{PKey, Private},
+    stack::Stack,
+    symm::Cipher,
+    x509::X509,
+};
+
+use crate::utils::{config::Smime, errors::ServiceError, mailer::MimeBody};
+
+/// Certificates and key, loaded from the files in the S/MIME settings
+#[derive(Default)]
+pub struct SmimeKeys {
+    signer: Option<(X509, PKey<Private>, Vec<X509>)>,
+    recipients: Vec<X509>,
+    recipient_mails: Vec<String>,
+}
+
+impl fmt::Debug for SmimeKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmimeKeys")
+            .field("signer", &self.signer.is_some())
+            .field("recipient_mails", &self.recipient_mails)
+            .finish()
+    }
+}
+
+fn key_error(path: &str, e: impl fmt::Display) -> ServiceError {
+    ServiceError::Conflict(format!("Can not load S/MIME file \"{path}\": {e}"))
+}
+
+fn read(path: &str) -> Result<Vec<u8>, ServiceError> {
+    fs::read(path).map_err(|e| key_error(path, e))
+}
+
+/// Mail addresses from the subject alternative names and the subject from the certificate.
+fn certificate_mails(cert: &X509) -> Vec<String> {
+    let mut mails: Vec<String> = cert
+        .subject_alt_names()
+        .iter()
+        .flatten()
+        .filter_map(|name| name.email().map(str::to_lowercase))
+        .collect();
+
+    for entry in cert.subject_name().entries_by_nid(Nid::PKCS9_EMAILADDRESS) {
+        if let Ok(mail) = entry.data().to_string() {
+            mails.push(mail.to_lowercase());
+        }
+    }
+
+    mails
+}
+
+/// Load the signing certificate with key, from PEM files or from a PKCS#12 file,
+/// and the certificates from the recipients.
+pub fn load_keys(smime: &Smime) -> Result<SmimeKeys, ServiceError> {
+    let mut keys = SmimeKeys::default();
+
+    if !smime.certificate.is_empty() {
+        let cert_file = read(&smime.certificate)?;
+
+        keys.signer = Some(if smime.private_key.is_empty() {
+            let parsed = Pkcs12::from_der(&cert_file)
+                .and_then(|p| p.parse2(&smime.password))
+                .map_err(|e| key_error(&smime.certificate, e))?;
+            let (Some(cert), Some(key)) = (parsed.cert, parsed.pkey) else {
+                return Err(key_error(&smime.certificate, "missing certificate or key"));
+            };
+            let chain = parsed
+                .ca
+                .map(|c| c.into_iter().collect())
+                .unwrap_or_default();
+
+            (cert, key, chain)
+        } else {
+            let mut chain = X509::stack_from_pem(&cert_file)
+                .map_err(|e| key_error(&smime.certificate, e))?
+                .into_iter();
+            let cert = chain
+                .next()
+                .ok_or_else(|| key_error(&smime.certificate, "no certificate found"))?;
+            let key = PKey::private_key_from_pem(&read(&smime.private_key)?)
+                .map_err(|e| key_error(&smime.private_key, e))?;
+
+            (cert, key, chain.collect())
+        });
+    }
+
+    for path in &smime.recipient_certificates {
+        for cert in X509::stack_from_pem(&read(path)?).map_err(|e| key_error(path, e))? {
+            keys.recipient_mails.extend(certificate_mails(&cert));
+            keys.recipients.push(cert);
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Recipients without a certificate, encrypted messages can not be sent to them.
+pub fn missing_certificates<'a>(smime: &Smime, recipients: &'a [String]) -> Vec<&'a str> {
+    if smime.keys.recipients.is_empty() {
+        return vec![];
+    }
+
+    recipients
+        .iter()
+        .filter(|r| {
+            r.parse::<Mailbox>().ok().is_none_or(|m| {
+                !smime
+                    .keys
+                    .recipient_mails
+                    .contains(&m.email.to_string().to_lowercase())
+            })
+        })
+        .map(|r| r.as_str())
+        .collect()
+}
+
+fn pkcs7_error(e: impl fmt::Display) -> ServiceError {
+    error!("S/MIME error: {e}");
+
+    ServiceError::InternalServerError
+}
+
+/// Sign the body as **multipart/signed**, the signature is detached, so clients
+/// without S/MIME can still read the mail.
+fn sign(keys: &SmimeKeys, body: MimeBody) -> Result<MimeBody, ServiceError> {
+    let Some((cert, key, chain)) = &keys.signer else {
+        return Ok(body);
+    };
+
+    let mut certs = Stack::new().map_err(pkcs7_error)?;
+
+    for c in chain {
+        certs.push(c.clone()).map_err(pkcs7_error)?;
+    }
+
+    // The line break at the end belongs to the following boundary, see RFC 2046
+    let formatted = body.formatted();
+    let content = formatted.strip_suffix(b"\r\n").unwrap_or(&formatted);
+
+    let signature = Pkcs7::sign(
+        cert,
+        key,
+        &certs,
+        content,
+        Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+    )
+    .and_then(|p| p.to_der())
+    .map_err(pkcs7_error)?;
+
+    let signed = MultiPart::signed(
+        "application/pkcs7-signature".to_string(),
+        "sha-256".to_string(),
+    );
+    let signed = match body {
+        MimeBody::Single(part) => signed.singlepart(part),
+        MimeBody::Multi(part) => signed.multipart(part),
+    };
+
+    Ok(MimeBody::Multi(
+        signed.singlepart(
+            SinglePart::builder()
+                .header(
+                    ContentType::parse("application/pkcs7-signature; name=\"smime.p7s\"").unwrap(),
+                )
+                .header(ContentDisposition::attachment("smime.p7s"))
+                .body(signature),
+        ),
+    ))
+}
+
+/// Encrypt the body for all recipient certificates as **application/pkcs7-mime**.
+fn encrypt(keys: &SmimeKeys, body: MimeBody) -> Result<MimeBody, ServiceError> {
+    if keys.recipients.is_empty() {
+        return Ok(body);
+    }
+
+    let mut certs = Stack::new().map_err(pkcs7_error)?;
+
+    for c in &keys.recipients {
+        certs.push(c.clone()).map_err(pkcs7_error)?;
+    }
+
+    let encrypted = Pkcs7::encrypt(
+        &certs,
+        &body.formatted(),
+        Cipher::aes_256_cbc(),
+        Pkcs7Flags::BINARY,
+    )
+    .and_then(|p| p.to_der())
+    .map_err(pkcs7_error)?;
+
+    Ok(MimeBody::Single(
+        SinglePart::builder()
+            .header(
+                ContentType::parse(
+                    "application/pkcs7-mime; smime-type=enveloped-data; name=\"smime.p7m\"",
+                )
+                .unwrap(),
+            )
+            .header(ContentDisposition::attachment("smime.p7m"))
+            .body(encrypted),
+    ))
+}
+
+/// Sign and encrypt the body, like it is configured. Messages are signed first,
+/// so the signature is also encrypted. Recipients without certificate stop the delivery.
+pub fn protect(
+    smime: &Smime,
+    recipients: &[String],
+    body: MimeBody,
+) -> Result<MimeBody, ServiceError> {
+    let missing = missing_certificates(smime, recipients);
+
+    if !missing.is_empty() {
+        error!("No S/MIME certificate for: {}", missing.join(", "));
+
+        return Err(ServiceError::Conflict(format!(
+            "No S/MIME certificate for: {}",
+            missing.join(", ")
+        )));
+    }
+
+    encrypt(&smime.keys, sign(&smime.keys, body)?)
+}
@@ -0,0 +1,192 @@
+use log::{debug, warn};
+use regex::Regex;
+
+use crate::utils::{
+    config::{Config, Recipients, SpamTarget},
+    errors::ServiceError,
+    mailer::Msg,
+};
+use crate::CONFIG;
+
+/// Spam rule with the compiled regex, rules are compiled once when the config is loaded.
+#[derive(Debug)]
+pub struct CompiledRule {
+    pub regex: Regex,
+    pub weight: f64,
+    pub targets: Vec<SpamTarget>,
+}
+
+/// Result from the spam check
+#[derive(Debug, PartialEq)]
+pub enum SpamVerdict {
+    Ham,
+    Quarantine(f64),
+    Reject(f64),
+}
+
+fn compile(pattern: &str) -> Result<Regex, ServiceError> {
+    Regex::new(pattern).map_err(|e| {
+        ServiceError::Conflict(format!("Invalid spam rule pattern \"{pattern}\": {e}"))
+    })
+}
+
+/// Compile spam rules from config.
+///
+/// Words from **block_words** become rules for subject and body, which always reject the message.
+pub fn compile_rules(config: &Config) -> Result<Vec<CompiledRule>, ServiceError> {
+    let mut rules = vec![];
+
+    for word in &config.mail.block_words {
+        rules.push(CompiledRule {
+            regex: compile(&format!(r"\b{word}\b"))?,
+            weight: f64::INFINITY,
+            targets: vec![SpamTarget::Subject, SpamTarget::Body],
+        });
+    }
+
+    for rule in &config.mail.spam_rules {
+        if rule.targets.is_empty() {
+            return Err(ServiceError::Conflict(format!(
+                "Spam rule \"{}\" has no targets",
+                rule.pattern
+            )));
+        }
+
+        rules.push(CompiledRule {
+            regex: compile(&rule.pattern)?,
+            weight: rule.weight,
+            targets: rule.targets.clone(),
+        });
+    }
+
+    Ok(rules)
+}
+
+/// Sum up the weights from all rules, which match one of their targets.
+/// Custom form fields are part of the body.
+pub fn score(rules: &[CompiledRule], msg: &Msg) -> f64 {
+    let mut total = 0.0;
+
+    for rule in rules {
+        let matched = rule.targets.iter().any(|target| match target {
+            SpamTarget::Subject => rule.regex.is_match(&msg.subject),
+            SpamTarget::Body => {
+                rule.regex.is_match(&msg.text)
+                    || msg.fields.values().any(|v| rule.regex.is_match(v))
+            }
+            SpamTarget::Sender => rule.regex.is_match(&msg.mail),
+            SpamTarget::AttachmentName => msg
+                .attachment
+                .iter()
+                .flatten()
+                .any(|(name, _)| rule.regex.is_match(name)),
+        });
+
+        if matched {
+            debug!("Spam rule \"{}\" matched: {}", rule.regex, rule.weight);
+            total += rule.weight;
+        }
+    }
+
+    total
+}
+
+/// Compare the spam score with the thresholds from the direction.
+/// A threshold of 0 disables quarantine.
+pub fn verdict(msg: &Msg, recipient: &Recipients) -> SpamVerdict {
+    let total = score(&CONFIG.spam_rules, msg);
+
+    if total >= recipient.spam_reject_score {
+        warn!(
+            "Reject message for \"{}\", spam score: {total}",
+            recipient.direction
+        );
+
+        SpamVerdict::Reject(total)
+    } else if recipient.spam_quarantine_score > 0.0 && total >= recipient.spam_quarantine_score {
+        warn!(
+            "Quarantine message for \"{}\", spam score: {total}",
+            recipient.direction
+        );
+
+        SpamVerdict::Quarantine(total)
+    } else {
+        SpamVerdict::Ham
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, weight: f64, targets: &[SpamTarget]) -> CompiledRule {
+        CompiledRule {
+            regex: compile(pattern).unwrap(),
+            weight,
+            targets: targets.to_vec(),
+        }
+    }
+
+    fn msg() -> Msg {
+        let mut msg = Msg {
+            mail: "someone@spam.example".to_string(),
+            subject: "Cheap offer".to_string(),
+            text: "Visit our casino".to_string(),
+            ..Msg::default()
+        };
+        msg.fields
+            .insert("company".to_string(), "Crypto Ltd".to_string());
+
+        msg
+    }
+
+    #[test]
+    fn weights_from_matching_rules() {
+        let rules = vec![
+            rule("(?i)cheap", 2.0, &[SpamTarget::Subject]),
+            rule("casino", 3.0, &[SpamTarget::Body]),
+            rule(r"@spam\.example$", 1.5, &[SpamTarget::Sender]),
+            rule("(?i)cheap", 10.0, &[SpamTarget::Body]),
+        ];
+
+        assert_eq!(score(&rules, &msg()), 6.5);
+    }
+
+    #[test]
+    fn custom_fields_are_body() {
+        let rules = vec![rule("Crypto", 4.0, &[SpamTarget::Body])];
+
+        assert_eq!(score(&rules, &msg()), 4.0);
+    }
+
+    #[test]
+    fn rule_counts_once_for_many_targets() {
+        let rules = vec![rule(
+            "(?i)casino|cheap",
+            2.0,
+            &[SpamTarget::Subject, SpamTarget::Body],
+        )];
+
+        assert_eq!(score(&rules, &msg()), 2.0);
+    }
+
+    #[test]
+    fn attachment_names() {
+        let rules = vec![rule(r"\.exe$", 5.0, &[SpamTarget::AttachmentName])];
+        let mut msg = msg();
+
+        assert_eq!(score(&rules, &msg), 0.0);
+
+        msg.attachment = Some(vec![("setup.exe".to_string(), vec![])]);
+
+        assert_eq!(score(&rules, &msg), 5.0);
+    }
+
+    #[test]
+    fn block_words_always_reject() {
+        let rules = vec![rule(r"\bcasino\b", f64::INFINITY, &[SpamTarget::Body])];
+
+        assert_eq!(score(&rules, &msg()), f64::INFINITY);
+        assert!(compile("(unclosed").is_err());
+    }
+}
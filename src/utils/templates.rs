@@ -0,0 +1,301 @@
+use std::fs;
+
+use minijinja::{context, Environment, ErrorKind, UndefinedBehavior, Value};
+use serde::Serialize;
+use voca_rs::Voca;
+
+use crate::utils::{config::Config, errors::ServiceError, fields::rows, mailer::Msg};
+use crate::CONFIG;
+
+/// Limit the work a template can do, so a broken loop can not block the worker.
+const TEMPLATE_FUEL: u64 = 100_000;
+
+pub const SUBJECT: &str = "subject";
+pub const TEXT: &str = "text";
+pub const HTML: &str = "html";
+pub const CONFIRM_SUBJECT: &str = "confirm.subject";
+pub const CONFIRM_TEXT: &str = "confirm.text";
+pub const CONFIRM_HTML: &str = "confirm.html";
+
+/// Confirmation to the user, when the direction has no own templates
+const DEFAULT_CONFIRM_SUBJECT: &str = "Received: {{ subject }}";
+const DEFAULT_CONFIRM_TEXT: &str =
+    "Thank you, we received your request and will get back to you soon.
+
+Your message:
+
+{{ quoted }}
+";
+
+#[derive(Serialize)]
+struct Field<'a> {
+    name: &'a str,
+    label: &'a str,
+    value: &'a str,
+}
+
+/// Template name from direction and kind, html templates end with **.html** to enable auto escaping.
+fn name(direction: &str, kind: &str) -> String {
+    if kind.ends_with(HTML) {
+        format!("{direction}.{kind}")
+    } else {
+        format!("{direction}.{kind}.txt")
+    }
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_fuel(Some(TEMPLATE_FUEL));
+    env.set_undefined_behavior(UndefinedBehavior::Chainable);
+
+    env
+}
+
+/// Load and compile the templates from all directions, errors in templates stop the config loading.
+pub fn compile_templates(config: &Config) -> Result<Environment<'static>, ServiceError> {
+    let mut env = environment();
+
+    for recipient in &config.mail.recipients {
+        let templates = &recipient.templates;
+        let confirmation = &recipient.confirmation;
+
+        for (kind, path) in [
+            (SUBJECT, &templates.subject),
+            (TEXT, &templates.text),
+            (HTML, &templates.html),
+            (CONFIRM_SUBJECT, &confirmation.subject),
+            (CONFIRM_TEXT, &confirmation.text),
+            (CONFIRM_HTML, &confirmation.html),
+        ] {
+            if path.is_empty() {
+                continue;
+            }
+
+            let source = fs::read_to_string(path).map_err(|e| {
+                ServiceError::Conflict(format!("Can not read template \"{path}\": {e}"))
+            })?;
+
+            env.add_template_owned(name(&recipient.direction, kind), source)
+                .map_err(|e| ServiceError::Conflict(format!("Invalid template \"{path}\": {e}")))?;
+        }
+    }
+
+    Ok(env)
+}
+
+/// Original text and custom fields from the user, with **>** in front of each line.
+fn quote(text: &str, field_list: &[Field]) -> String {
+    let mut lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+
+    if !field_list.is_empty() {
+        lines.push(String::new());
+        lines.extend(
+            field_list
+                .iter()
+                .map(|f| format!("{}: {}", f.label, f.value)),
+        );
+    }
+
+    lines
+        .iter()
+        .map(|l| format!("> {l}").trim_end().to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Template context with all message fields and the request metadata.
+pub fn context(msg: &Msg, text: &str) -> Value {
+    let field_config = msg
+        .direction
+        .as_ref()
+        .and_then(|d| CONFIG.recipient(d))
+        .map(|r| r.fields.as_slice())
+        .unwrap_or_default();
+    let field_list: Vec<Field> = rows(&msg.fields, field_config)
+        .into_iter()
+        .map(|(name, label, value)| Field { name, label, value })
+        .collect();
+    let attachments: Vec<&str> = msg
+        .attachment
+        .iter()
+        .flatten()
+        .map(|(name, _)| name.as_str())
+        .collect();
+
+    context! {
+        direction => msg.direction,
+        mail => msg.mail,
+        subject => msg.subject,
+        text => text,
+        quoted => quote(&text._strip_tags(), &field_list),
+        fields => msg.fields,
+        field_list => field_list,
+        attachments => attachments,
+        ip => msg.meta.ip,
+        user_agent => msg.meta.user_agent,
+        received => msg.meta.received,
+    }
+}
+
+/// Render the confirmation template from the direction, or the default confirmation.
+pub fn render_confirmation(
+    direction: &str,
+    ctx: &Value,
+) -> Result<(String, String, bool), ServiceError> {
+    confirmation(&CONFIG.template_env, direction, ctx)
+}
+
+fn confirmation(
+    env: &Environment,
+    direction: &str,
+    ctx: &Value,
+) -> Result<(String, String, bool), ServiceError> {
+    let subject = match render_with(env, direction, CONFIRM_SUBJECT, ctx)? {
+        Some(subject) => subject,
+        None => render_default(env, DEFAULT_CONFIRM_SUBJECT, ctx)?,
+    };
+
+    if let Some(html) = render_with(env, direction, CONFIRM_HTML, ctx)? {
+        return Ok((subject, html, true));
+    }
+
+    let text = match render_with(env, direction, CONFIRM_TEXT, ctx)? {
+        Some(text) => text,
+        None => render_default(env, DEFAULT_CONFIRM_TEXT, ctx)?,
+    };
+
+    Ok((subject, text, false))
+}
+
+fn render_default(env: &Environment, source: &str, ctx: &Value) -> Result<String, ServiceError> {
+    env.render_str(source, ctx)
+        .map_err(|e| ServiceError::Conflict(e.to_string()))
+}
+
+/// Render the template from the direction, returns **None** when the direction has no such template.
+pub fn render(direction: &str, kind: &str, ctx: &Value) -> Result<Option<String>, ServiceError> {
+    render_with(&CONFIG.template_env, direction, kind, ctx)
+}
+
+fn render_with(
+    env: &Environment,
+    direction: &str,
+    kind: &str,
+    ctx: &Value,
+) -> Result<Option<String>, ServiceError> {
+    let template = match env.get_template(&name(direction, kind)) {
+        Ok(template) => template,
+        Err(e) if e.kind() == ErrorKind::TemplateNotFound => return Ok(None),
+        Err(e) => return Err(ServiceError::Conflict(e.to_string())),
+    };
+
+    template
+        .render(ctx)
+        .map(Some)
+        .map_err(|e| ServiceError::Conflict(format!("Template error in {direction}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(templates: &[(&str, &str)]) -> Environment<'static> {
+        let mut env = environment();
+
+        for (kind, source) in templates {
+            env.add_template_owned(name("contact", kind), source.to_string())
+                .unwrap();
+        }
+
+        env
+    }
+
+    fn ctx() -> Value {
+        context! {
+            direction => "contact",
+            mail => "user@example.org",
+            subject => "Question",
+            text => "Hello\nWorld",
+            quoted => "> Hello\n> World",
+            field_list => vec![Field { name: "phone", label: "Phone", value: "123" }],
+        }
+    }
+
+    #[test]
+    fn render_templates() {
+        let env = env(&[
+            (SUBJECT, "[Website {{ direction }}] {{ subject }}"),
+            (
+                TEXT,
+                "From: {{ mail }}\n{% for f in field_list %}{{ f.label }}: {{ f.value }}\n{% endfor %}{{ missing.value }}",
+            ),
+        ]);
+
+        assert_eq!(
+            render_with(&env, "contact", SUBJECT, &ctx()).unwrap(),
+            Some("[Website contact] Question".to_string())
+        );
+        // undefined values are empty, also when they are chained
+        assert_eq!(
+            render_with(&env, "contact", TEXT, &ctx()).unwrap(),
+            Some("From: user@example.org\nPhone: 123\n".to_string())
+        );
+        assert_eq!(render_with(&env, "contact", HTML, &ctx()).unwrap(), None);
+        assert_eq!(render_with(&env, "support", SUBJECT, &ctx()).unwrap(), None);
+    }
+
+    #[test]
+    fn html_escaping() {
+        let env = env(&[(HTML, "<p>{{ subject }}</p>")]);
+        let ctx = context! { subject => "<script>alert(1)</script>" };
+
+        assert_eq!(
+            render_with(&env, "contact", HTML, &ctx).unwrap(),
+            Some("<p>&lt;script&gt;alert(1)&lt;&#x2f;script&gt;</p>".to_string())
+        );
+    }
+
+    #[test]
+    fn fuel_exhaustion() {
+        let env = env(&[(
+            TEXT,
+            "{% for i in range(1000) %}{% for j in range(1000) %}x{% endfor %}{% endfor %}",
+        )]);
+
+        assert!(matches!(
+            render_with(&env, "contact", TEXT, &ctx()),
+            Err(ServiceError::Conflict(e)) if e.contains("fuel")
+        ));
+    }
+
+    #[test]
+    fn default_confirmation() {
+        let (subject, text, html) = confirmation(&env(&[]), "contact", &ctx()).unwrap();
+
+        assert_eq!(subject, "Received: Question");
+        assert!(text.ends_with("Your message:\n\n> Hello\n> World"));
+        assert!(!html);
+
+        let env = env(&[(CONFIRM_HTML, "<p>Thanks</p>")]);
+        let (subject, text, html) = confirmation(&env, "contact", &ctx()).unwrap();
+
+        assert_eq!(subject, "Received: Question");
+        assert_eq!(text, "<p>Thanks</p>");
+        assert!(html);
+    }
+
+    #[test]
+    fn quote_fields() {
+        let fields = [Field {
+            name: "phone",
+            label: "Phone",
+            value: "123",
+        }];
+
+        assert_eq!(
+            quote("Hello\n\nWorld", &fields),
+            "> Hello\n>\n> World\n>\n> Phone: 123"
+        );
+        assert_eq!(quote("Hello", &[]), "> Hello");
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashMap, fs, path::Path, sync::RwLock, time::Duration};
+
+use lazy_static::lazy_static;
+use lettre::{
+    address::Envelope,
+    transport::smtp::{
+        authentication::Credentials,
+        client::{Certificate, Tls, TlsParameters},
+        PoolConfig,
+    },
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use log::{debug, error, trace, warn};
+
+use crate::utils::{
+    config::{Relay, Security},
+    errors::ServiceError,
+};
+use crate::CONFIG;
+
+lazy_static! {
+    static ref TRANSPORTS: RwLock<HashMap<String, AsyncSmtpTransport<Tokio1Executor>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Error from a delivery attempt, permanent errors should not be retried.
+#[derive(Debug)]
+pub struct SendError {
+    pub message: String,
+    pub permanent: bool,
+}
+
+/// Build the SMTP transport for a relay.
+///
+/// The connection uses the configured **port** and **security** mode. For TLS connections a custom
+/// CA bundle can be added, which is needed for internal relays with their own certificates.
+/// With **skip_verify** the certificate is not validated at all, this should only be used for testing.
+///
+/// Connections are pooled, so they can be reused for the next messages.
+fn build_transport(relay: &Relay) -> Result<AsyncSmtpTransport<Tokio1Executor>, ServiceError> {
+    let mut builder =
+        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&relay.smtp).port(relay.port);
+
+    if relay.security() != Security::None {
+        let mut tls_builder = TlsParameters::builder(relay.smtp.clone())
+            .dangerous_accept_invalid_certs(relay.skip_verify);
+
+        if !relay.ca_bundle.is_empty() {
+            let pem = fs::read(&relay.ca_bundle)?;
+            tls_builder = tls_builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+
+        let parameters = tls_builder.build()?;
+
+        builder = match relay.security() {
+            Security::Tls => builder.tls(Tls::Wrapper(parameters)),
+            _ => builder.tls(Tls::Required(parameters)),
+        };
+    }
+
+    // local relays often accept mails without authentication
+    if !relay.password.is_empty() {
+        let credentials = Credentials::new(relay.user.clone(), relay.password.clone());
+        builder = builder.credentials(credentials);
+    }
+
+    let pool = PoolConfig::new()
+        .max_size(relay.pool.max_size)
+        .min_idle(relay.pool.min_idle)
+        .idle_timeout(Duration::from_secs(relay.pool.idle_timeout_seconds));
+
+    Ok(builder.pool_config(pool).build())
+}
+
+/// Get the shared SMTP transport from a relay, it is build on first usage.
+pub fn transport(name: &str) -> Result<AsyncSmtpTransport<Tokio1Executor>, ServiceError> {
+    if let Some(mailer) = TRANSPORTS.read().ok().and_then(|t| t.get(name).cloned()) {
+        return Ok(mailer);
+    }
+
+    let relay = CONFIG
+        .relay(name)
+        .ok_or_else(|| ServiceError::Conflict(format!("Unknown relay: {name}")))?;
+    let mailer = build_transport(relay)?;
+
+    if let Ok(mut transports) = TRANSPORTS.write() {
+        transports.insert(name.to_string(), mailer.clone());
+    }
+
+    debug!(
+        "SMTP transport {name} to {}:{} ready",
+        relay.smtp, relay.port
+    );
+
+    Ok(mailer)
+}
+
+/// Drop all shared SMTP transports and build them new, with the relay settings from the current config.
+/// Open connections from the old pools are closed, when they are not in use anymore.
+pub fn reload_transports() {
+    if let Ok(mut transports) = TRANSPORTS.write() {
+        transports.clear();
+    }
+
+    for relay in CONFIG.all_relays() {
+        if let Err(e) = transport(&relay.name) {
+            error!("Build SMTP transport {}: {e}", relay.name);
+        }
+    }
+}
+
+/// Send raw message to the mail server, this is called from the queue worker.
+///
+/// The relays are tried in the given order. When a relay is not reachable or answers with a
+/// temporary error, the next relay is used. A permanent error stops the delivery, because
+/// the message would most likely be rejected from the other relays too.
+pub async fn send(relays: &[String], envelope: &Envelope, message: &[u8]) -> Result<(), SendError> {
+    let mut last_error = SendError {
+        message: "No relay available".to_string(),
+        permanent: false,
+    };
+
+    trace!("Mail: {}", String::from_utf8_lossy(message));
+
+    for name in relays {
+        let mailer = match transport(name) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Relay {name} not usable: {e}");
+                last_error.message = e.to_string();
+                continue;
+            }
+        };
+
+        match mailer.send_raw(envelope, message).await {
+            Ok(_) => {
+                debug!("Message sent over relay {name}");
+                archive(envelope, message).await;
+
+                return Ok(());
+            }
+            Err(e) if e.is_permanent() => {
+                return Err(SendError {
+                    message: e.to_string(),
+                    permanent: true,
+                });
+            }
+            Err(e) => {
+                warn!("Relay {name} failed: {e}");
+                last_error.message = e.to_string();
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Backup mail to file if mail_archive is set
+async fn archive(envelope: &Envelope, message: &[u8]) {
+    if !CONFIG.mail_archive.is_empty() && Path::new(&CONFIG.mail_archive).is_dir() {
+        let backup = AsyncFileTransport::<Tokio1Executor>::new(Path::new(&CONFIG.mail_archive));
+
+        if let Err(e) = backup.send_raw(envelope, message).await {
+            error!("Archive mail: {e:?}");
+        }
+    }
+}